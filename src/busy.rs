@@ -0,0 +1,91 @@
+use anyhow::Result;
+use log::debug;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::candidates::FileInfo;
+
+/// Spots files that something is probably still writing.
+pub struct BusyCheck {
+    /// `(dev, inode)` of every file some process has open for writing
+    open_for_writing: HashSet<(u64, u64)>,
+    /// anything modified at or after this is still settling
+    quiet_since: u64,
+}
+
+impl BusyCheck {
+    pub fn new(quiet_since: u64) -> Result<BusyCheck> {
+        Ok(BusyCheck {
+            open_for_writing: open_for_writing()?,
+            quiet_since,
+        })
+    }
+
+    /// Why `file` shouldn't be deleted yet, if it shouldn't.
+    pub fn why_busy(&self, file: &FileInfo) -> Option<&'static str> {
+        if file.modified >= self.quiet_since {
+            return Some("modified too recently");
+        }
+        if self.open_for_writing.contains(&(file.dev, file.inode)) {
+            return Some("open for writing");
+        }
+        if is_locked(&file.path) {
+            return Some("locked");
+        }
+        None
+    }
+}
+
+/// Holds an exclusive `flock`, so won't let us take a shared one.
+fn is_locked(path: &Path) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    matches!(
+        Flock::lock(file, FlockArg::LockSharedNonblock),
+        Err((_, Errno::EWOULDBLOCK))
+    )
+}
+
+/// Every file open for writing by any process we can see into, from `/proc/*/fd`.
+fn open_for_writing() -> Result<HashSet<(u64, u64)>> {
+    let mut files = HashSet::new();
+    for process in fs::read_dir("/proc")? {
+        let process = process?.path();
+        let Ok(fds) = fs::read_dir(process.join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let fdinfo = process.join("fdinfo").join(fd.file_name());
+            if !fdinfo_is_writable(&fdinfo) {
+                continue;
+            }
+            match fs::metadata(fd.path()) {
+                Ok(metadata) if metadata.is_file() => {
+                    files.insert((metadata.dev(), metadata.ino()));
+                }
+                Ok(_) => {}
+                Err(e) => debug!("can't follow {:?}: {e}", fd.path()),
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn fdinfo_is_writable(fdinfo: &Path) -> bool {
+    let Ok(fdinfo) = fs::read_to_string(fdinfo) else {
+        return false;
+    };
+    fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| i32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| {
+            let mode = flags & nix::libc::O_ACCMODE;
+            mode == nix::libc::O_WRONLY || mode == nix::libc::O_RDWR
+        })
+}
@@ -0,0 +1,939 @@
+use anyhow::{Context, Result, anyhow, bail};
+use log::{info, warn};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::errors::Failures;
+use crate::index::Index;
+use crate::pattern::Pattern;
+use crate::pin;
+use crate::service;
+use crate::usage::Space;
+use crate::walk::walk_files;
+
+#[derive(Clone)]
+pub struct FileInfo {
+    pub path: PathBuf,
+    pub modified: u64,
+    /// apparent size
+    pub size: u64,
+    /// space actually used, from `st_blocks`
+    pub allocated: u64,
+    pub nlink: u64,
+    pub dev: u64,
+    pub inode: u64,
+}
+
+impl FileInfo {
+    /// Unlinking a file with other links frees nothing.
+    pub fn frees(&self) -> Space {
+        if self.nlink > 1 {
+            return Space::default();
+        }
+        Space {
+            bytes: self.allocated,
+            inodes: 1,
+        }
+    }
+}
+
+/// A recording: the matching files sharing a stem, and anything else sharing it, like
+/// the `clip.jpg` thumbnail and `clip.json` next to `clip.mp4`. Deleted as a unit.
+#[derive(Clone)]
+pub struct Candidate {
+    /// matching files first, the one matching the earliest extension or include first,
+    /// which names the recording
+    pub files: Vec<FileInfo>,
+    /// how many of `files` matched
+    pub matching: usize,
+    /// of the newest file
+    pub modified: u64,
+    /// freed by removing all the files
+    pub size: u64,
+    /// index of the first `--tier` the recording matched, or the number of tiers if none
+    pub tier: usize,
+    pub camera: String,
+}
+
+impl Candidate {
+    pub fn new(files: Vec<FileInfo>, matching: usize, tier: usize, camera: String) -> Candidate {
+        Candidate {
+            modified: files.iter().map(|f| f.modified).max().unwrap_or_default(),
+            size: files.iter().map(|f| f.frees().bytes).sum(),
+            files,
+            matching,
+            tier,
+            camera,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.files[0].path
+    }
+
+    /// Whether every matching file has one of `extensions`, so a snapshot with a sidecar
+    /// counts, but not a video with a thumbnail.
+    pub fn only_has_extensions(&self, extensions: &[OsString]) -> bool {
+        self.files[..self.matching].iter().all(|file| {
+            file.path
+                .extension()
+                .is_some_and(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)))
+        })
+    }
+
+    pub fn space(&self) -> Space {
+        let mut space = Space::default();
+        for file in &self.files {
+            space += file.frees();
+        }
+        space
+    }
+
+    /// Lowest tier, then oldest, first. The path breaks ties, so the order doesn't depend
+    /// on the order of the walk.
+    pub fn age_order(&self) -> (usize, u64, &Path) {
+        (self.tier, self.modified, self.path())
+    }
+}
+
+/// What to consider for deletion, and how to organise it.
+pub struct Selection {
+    pub filter_extensions: Vec<OsString>,
+    pub case_sensitive_extensions: bool,
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub camera_depth: usize,
+    pub tiers: Vec<Pattern>,
+    /// not walked at all, like the quarantine
+    pub skip_dirs: Vec<PathBuf>,
+    pub walk_threads: usize,
+    /// where to keep an index of the directories, to only read those that have changed
+    pub index: Option<PathBuf>,
+    /// only keep as many of the oldest recordings as it takes to free this much, and
+    /// those modified before `expire_before`, leaving out those modified since
+    /// `retain_from`, which are too young to delete
+    pub budget: Option<Space>,
+    pub expire_before: Option<u64>,
+    pub retain_from: Option<u64>,
+    /// recordings already dealt with, by their first file
+    pub passed_over: HashSet<PathBuf>,
+}
+
+impl Selection {
+    /// The position of the first include the file matches, or of its extension if there
+    /// are no includes, so the file matching the earliest can name its recording, like
+    /// `clip.mp4` rather than its `clip.jpg` thumbnail. Excludes are checked separately,
+    /// as they also stop a file being deleted alongside a matching one.
+    fn matches(&self, relative: &Path) -> Option<usize> {
+        if !self.include.is_empty() {
+            return self.include.iter().position(|p| p.is_match(relative));
+        }
+        let name = relative.file_name()?;
+        self.filter_extensions
+            .iter()
+            .position(|ext| self.has_extension(name, ext))
+    }
+
+    fn has_extension(&self, name: &OsStr, ext: &OsStr) -> bool {
+        let (name, ext) = (name.as_bytes(), ext.as_bytes());
+        if name.len() <= ext.len() || name[name.len() - ext.len() - 1] != b'.' {
+            return false;
+        }
+        let suffix = &name[name.len() - ext.len()..];
+        if self.case_sensitive_extensions {
+            suffix == ext
+        } else {
+            suffix.eq_ignore_ascii_case(ext)
+        }
+    }
+
+    /// What the files of a recording share: the path without the longest extension in
+    /// `--filter-extensions` it has, so one like `mp4.part` is taken off as a whole, or
+    /// else without its last extension.
+    pub fn stem(&self, path: &Path) -> PathBuf {
+        let Some(name) = path.file_name() else {
+            return path.to_path_buf();
+        };
+        let longest = self
+            .filter_extensions
+            .iter()
+            .filter(|ext| self.has_extension(name, ext))
+            .map(|ext| ext.len())
+            .max();
+        match longest {
+            Some(len) => {
+                let name = name.as_bytes();
+                path.with_file_name(OsStr::from_bytes(&name[..name.len() - len - 1]))
+            }
+            None => path.with_extension(""),
+        }
+    }
+
+    /// The recording made of `files`, which share a stem, leaving out those excluded,
+    /// unless none of them match, or any of them are pinned, with a sidecar in
+    /// `pin_sidecars` or the xattr.
+    pub fn recording(
+        &self,
+        directories: &[PathBuf],
+        files: impl IntoIterator<Item = FileInfo>,
+        pin_sidecars: &HashSet<PathBuf>,
+    ) -> Recording {
+        let mut files: Vec<(FileInfo, Option<usize>)> = files
+            .into_iter()
+            .filter_map(|file| {
+                let relative = relative_to(directories, &file.path);
+                if self.exclude.iter().any(|p| p.is_match(relative)) {
+                    return None;
+                }
+                let matched = self.matches(relative);
+                Some((file, matched))
+            })
+            .collect();
+        if !files.iter().any(|(_, matched)| matched.is_some()) {
+            return Recording::Unmatched;
+        }
+        if files
+            .iter()
+            .any(|(file, _)| pin::is_pinned(&file.path, pin_sidecars))
+        {
+            return Recording::Pinned;
+        }
+        files.sort_unstable_by(|(a, a_matched), (b, b_matched)| {
+            (a_matched.is_none(), a_matched, &a.path).cmp(&(
+                b_matched.is_none(),
+                b_matched,
+                &b.path,
+            ))
+        });
+        let matching = files
+            .iter()
+            .filter(|(_, matched)| matched.is_some())
+            .count();
+        let files: Vec<FileInfo> = files.into_iter().map(|(file, _)| file).collect();
+        let relative = relative_to(directories, &files[0].path);
+        let tier = self
+            .tiers
+            .iter()
+            .position(|tier| tier.is_match(relative))
+            .unwrap_or(self.tiers.len());
+        let camera = camera_of(relative, self.camera_depth);
+        Recording::Found(Candidate::new(files, matching, tier, camera))
+    }
+}
+
+/// What files sharing a stem come to.
+pub enum Recording {
+    /// none of them match, so they're left alone
+    Unmatched,
+    Pinned,
+    Found(Candidate),
+}
+
+/// Past `after`, only keep one recording per `keep_one_per` from a series.
+#[derive(Debug, Clone)]
+pub struct ThinRule {
+    pub after: Duration,
+    pub keep_one_per: Duration,
+}
+
+/// Matching files, lowest tier then oldest first, split up by the camera that recorded them.
+#[derive(Default)]
+pub struct Cameras {
+    cameras: BTreeMap<String, Camera>,
+    /// allocated for every file below the directories, matching or not, like `du`
+    pub total_bytes: u64,
+    /// whether recordings were left out to stay within a budget
+    pub incomplete: bool,
+    /// recordings left out of the budget for being too young to delete
+    pub held_back: usize,
+}
+
+#[derive(Default)]
+struct Camera {
+    files: VecDeque<Candidate>,
+    bytes: u64,
+    quota: Option<u64>,
+}
+
+impl Cameras {
+    pub fn set_quota(&mut self, camera: &str, quota: u64) -> bool {
+        match self.cameras.get_mut(camera) {
+            Some(camera) => {
+                camera.quota = Some(quota);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn over_quota(&self, camera: &str) -> bool {
+        self.cameras
+            .get(camera)
+            .and_then(|c| c.quota.map(|quota| c.bytes > quota))
+            .unwrap_or(false)
+    }
+
+    /// Take out every file modified before `cutoff`, oldest first.
+    pub fn take_older_than(&mut self, cutoff: u64) -> Vec<Candidate> {
+        let mut taken = Vec::new();
+        for camera in self.cameras.values_mut() {
+            let (old, keep) = camera.files.drain(..).partition(|f| f.modified < cutoff);
+            camera.files = keep;
+            camera.bytes -= old.iter().map(|f: &Candidate| f.size).sum::<u64>();
+            taken.extend(old);
+        }
+        taken.sort_unstable_by_key(|f| f.modified);
+        taken
+    }
+
+    /// Take out the recordings thinned away, oldest first. A camera's recordings for which
+    /// `in_series` is true are split into windows by the rule for the greatest age each has
+    /// reached, and only the oldest in each window is kept.
+    pub fn take_thinned(
+        &mut self,
+        now: u64,
+        rules: &[ThinRule],
+        in_series: impl Fn(&Candidate) -> bool,
+    ) -> Vec<Candidate> {
+        let mut taken = Vec::new();
+        for camera in self.cameras.values_mut() {
+            let mut series: Vec<usize> = (0..camera.files.len())
+                .filter(|&i| in_series(&camera.files[i]))
+                .collect();
+            series.sort_by_key(|&i| camera.files[i].modified);
+
+            let mut windows = HashSet::new();
+            let mut thinned = HashSet::new();
+            for i in series {
+                let modified = camera.files[i].modified;
+                let age = now.saturating_sub(modified);
+                let Some(rule) = rules
+                    .iter()
+                    .filter(|rule| age >= rule.after.as_secs())
+                    .max_by_key(|rule| rule.after)
+                else {
+                    continue;
+                };
+                let window = rule.keep_one_per.as_secs().max(1);
+                if !windows.insert((window, modified / window)) {
+                    thinned.insert(i);
+                }
+            }
+            if thinned.is_empty() {
+                continue;
+            }
+
+            for (i, file) in std::mem::take(&mut camera.files).into_iter().enumerate() {
+                if thinned.contains(&i) {
+                    camera.bytes -= file.size;
+                    taken.push(file);
+                } else {
+                    camera.files.push_back(file);
+                }
+            }
+        }
+        taken.sort_unstable_by_key(|f| f.modified);
+        taken
+    }
+
+    /// Stop offering files modified at or after `floor`. They still count towards their
+    /// camera's size, as they're still on disk.
+    pub fn hold_back_from(&mut self, floor: u64) -> usize {
+        let mut held_back = 0;
+        for camera in self.cameras.values_mut() {
+            let before = camera.files.len();
+            camera.files.retain(|f| f.modified < floor);
+            held_back += before - camera.files.len();
+        }
+        held_back
+    }
+
+    pub fn pop_front(&mut self, camera: &str) -> Option<Candidate> {
+        let camera = self.cameras.get_mut(camera)?;
+        let candidate = camera.files.pop_front()?;
+        camera.bytes = camera.bytes.saturating_sub(candidate.size);
+        Some(candidate)
+    }
+
+    /// The camera to delete from next: the one holding the globally oldest file in the
+    /// lowest tier or, with `fair_share`, the one furthest over its share among those with
+    /// files in the lowest tier. A camera's share is its quota, if it has one, or an equal
+    /// split of all the matching bytes.
+    pub fn next_camera(&self, fair_share: bool) -> Option<String> {
+        let lowest_tier = self
+            .cameras
+            .values()
+            .filter_map(|c| c.files.front().map(|f| f.tier))
+            .min()?;
+        let non_empty = self
+            .cameras
+            .iter()
+            .filter(|(_, c)| c.files.front().is_some_and(|f| f.tier == lowest_tier));
+        let chosen = if fair_share {
+            let equal_share = self.bytes() / (self.cameras.len().max(1) as u64);
+            non_empty.max_by_key(|(_, c)| {
+                i128::from(c.bytes) - i128::from(c.quota.unwrap_or(equal_share))
+            })
+        } else {
+            non_empty.min_by_key(|(_, c)| c.files.front().map(|f| f.modified))
+        };
+        chosen.map(|(name, _)| name.to_string())
+    }
+
+    fn bytes(&self) -> u64 {
+        self.cameras.values().map(|c| c.bytes).sum()
+    }
+
+    /// Cameras holding `candidates`, which are in `age_order` already.
+    pub fn from_ordered(
+        total_bytes: u64,
+        candidates: impl IntoIterator<Item = Candidate>,
+    ) -> Cameras {
+        let mut cameras = Cameras {
+            total_bytes,
+            ..Cameras::default()
+        };
+        for candidate in candidates {
+            cameras.push(candidate.camera.clone(), candidate);
+        }
+        cameras
+    }
+
+    fn push(&mut self, camera: String, candidate: Candidate) {
+        let camera = self.cameras.entry(camera).or_default();
+        camera.bytes += candidate.size;
+        camera.files.push_back(candidate);
+    }
+}
+
+/// `path`, relative to whichever of `directories` it's in.
+pub fn relative_to<'p>(directories: &[PathBuf], path: &'p Path) -> &'p Path {
+    directories
+        .iter()
+        .find_map(|directory| path.strip_prefix(directory).ok())
+        .unwrap_or(path)
+}
+
+/// The camera a file belongs to: the first `depth` directories of its relative path,
+/// or `.` for files that aren't that deep.
+pub fn camera_of(relative: &Path, depth: usize) -> String {
+    let parents: Vec<_> = relative
+        .parent()
+        .map(|parent| parent.iter().collect())
+        .unwrap_or_default();
+    if depth == 0 || parents.len() < depth {
+        return ".".to_string();
+    }
+    parents[..depth]
+        .iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Everything that could be deleted from `directories`, as one set of cameras.
+/// Cameras with the same name in different directories are treated as one.
+pub fn find_matching_files(
+    directories: &[PathBuf],
+    selection: &Selection,
+    failures: &mut Failures,
+) -> Result<Cameras> {
+    let mut finder = Finder::new(directories, selection);
+    let mut index = selection.index.as_deref().map(Index::load);
+    walk_files(
+        directories,
+        &selection.skip_dirs,
+        selection.walk_threads,
+        index.as_mut(),
+        |dir_files| {
+            service::keep_alive();
+            finder.add_dir(dir_files, failures);
+        },
+    )?;
+    if let (Some(path), Some(index)) = (&selection.index, &index)
+        && let Err(e) = index.save(path)
+    {
+        warn!("not saving the index: {e:#}");
+    }
+    Ok(finder.finish())
+}
+
+/// Sorts the files below `directories` into cameras, one directory at a time, however
+/// they were found.
+pub struct Finder<'f> {
+    directories: &'f [PathBuf],
+    selection: &'f Selection,
+    total_bytes: u64,
+    hard_linked: HashSet<u64>,
+    pinned: usize,
+    kept: Kept,
+}
+
+impl<'f> Finder<'f> {
+    pub fn new(directories: &'f [PathBuf], selection: &'f Selection) -> Finder<'f> {
+        Finder {
+            directories,
+            selection,
+            total_bytes: 0,
+            hard_linked: HashSet::new(),
+            pinned: 0,
+            kept: Kept::new(
+                selection.budget,
+                selection.expire_before,
+                selection.retain_from,
+            ),
+        }
+    }
+
+    /// Add every file in a directory. A recording's files, and their pin sidecars, are all
+    /// in the same directory, so they have to come together.
+    pub fn add_dir(
+        &mut self,
+        dir_files: impl IntoIterator<Item = Result<FileInfo>>,
+        failures: &mut Failures,
+    ) {
+        let (directories, selection) = (self.directories, self.selection);
+        let mut by_stem: HashMap<PathBuf, Vec<FileInfo>> = HashMap::new();
+        let mut pin_sidecars = HashSet::new();
+        for file in dir_files {
+            let file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    failures.record(&e);
+                    continue;
+                }
+            };
+            if file.nlink == 1 || self.hard_linked.insert(file.inode) {
+                self.total_bytes += file.allocated;
+            }
+            if file.path.extension().is_some_and(|ext| ext == "keep") {
+                pin_sidecars.insert(file.path);
+                continue;
+            }
+            by_stem
+                .entry(selection.stem(&file.path))
+                .or_default()
+                .push(file);
+        }
+
+        for (_, files) in by_stem {
+            match selection.recording(directories, files, &pin_sidecars) {
+                Recording::Unmatched => {}
+                Recording::Pinned => self.pinned += 1,
+                Recording::Found(candidate) => {
+                    if !selection.passed_over.contains(candidate.path()) {
+                        self.kept.push(candidate);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> Cameras {
+        if self.pinned > 0 {
+            info!("skipping {} pinned recordings", self.pinned);
+        }
+        let (incomplete, held_back) = (self.kept.dropped, self.kept.held_back);
+        let mut matches = self.kept.into_candidates();
+        matches.sort_unstable_by(|a, b| a.age_order().cmp(&b.age_order()));
+        let mut cameras = Cameras::from_ordered(self.total_bytes, matches);
+        cameras.incomplete = incomplete;
+        cameras.held_back = held_back;
+        cameras
+    }
+}
+
+/// The recordings found so far or, with a budget, only as many of the oldest as it takes
+/// to cover it, so memory doesn't grow with the whole tree.
+struct Kept {
+    budget: Option<Space>,
+    /// past max age, so kept whatever the budget
+    expire_before: Option<u64>,
+    /// too young to delete, so only counted, rather than taking up the budget
+    retain_from: Option<u64>,
+    held_back: usize,
+    kept: Vec<Candidate>,
+    /// the newest on top, to be dropped once the rest cover the budget
+    oldest: BinaryHeap<ByAge>,
+    oldest_space: Space,
+    dropped: bool,
+}
+
+impl Kept {
+    fn new(budget: Option<Space>, expire_before: Option<u64>, retain_from: Option<u64>) -> Kept {
+        Kept {
+            budget,
+            expire_before,
+            retain_from,
+            held_back: 0,
+            kept: Vec::new(),
+            oldest: BinaryHeap::new(),
+            oldest_space: Space::default(),
+            dropped: false,
+        }
+    }
+
+    fn push(&mut self, candidate: Candidate) {
+        let Some(budget) = self.budget else {
+            self.kept.push(candidate);
+            return;
+        };
+        if self
+            .expire_before
+            .is_some_and(|expire_before| candidate.modified < expire_before)
+        {
+            self.kept.push(candidate);
+            return;
+        }
+        if self
+            .retain_from
+            .is_some_and(|retain_from| candidate.modified >= retain_from)
+        {
+            self.held_back += 1;
+            return;
+        }
+        if candidate.space().is_empty() {
+            // it could never help
+            return;
+        }
+        self.oldest_space += candidate.space();
+        self.oldest.push(ByAge(candidate));
+        while let Some(newest) = self.oldest.peek()
+            && self
+                .oldest_space
+                .saturating_sub(newest.0.space())
+                .covers(&budget)
+        {
+            self.oldest_space = self.oldest_space.saturating_sub(newest.0.space());
+            self.oldest.pop();
+            self.dropped = true;
+        }
+    }
+
+    fn into_candidates(self) -> Vec<Candidate> {
+        let mut candidates = self.kept;
+        candidates.extend(self.oldest.into_iter().map(|ByAge(candidate)| candidate));
+        candidates
+    }
+}
+
+/// Orders candidates by `Candidate::age_order`.
+struct ByAge(Candidate);
+
+impl PartialEq for ByAge {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for ByAge {}
+
+impl PartialOrd for ByAge {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByAge {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.age_order().cmp(&other.0.age_order())
+    }
+}
+
+/// The current state of a single file, which must be a regular file.
+pub fn file_info(path: &Path) -> Result<FileInfo> {
+    let metadata = fs::symlink_metadata(path).with_context(|| anyhow!("reading {path:?}"))?;
+    if !metadata.is_file() {
+        bail!("{path:?} is not a regular file");
+    }
+    metadata_to_file_info(path, &metadata)
+}
+
+pub fn metadata_to_file_info(path: &Path, metadata: &fs::Metadata) -> Result<FileInfo> {
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(FileInfo {
+        path: path.to_path_buf(),
+        modified,
+        size: metadata.len(),
+        allocated: metadata.blocks() * 512,
+        nlink: metadata.nlink(),
+        dev: metadata.dev(),
+        inode: metadata.ino(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+    /// a multiple of every window used, so recordings a minute apart share one
+    const BASE: u64 = 1_000_000_200;
+
+    fn file(path: &str, modified: u64) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from(path),
+            modified,
+            size: 1000,
+            allocated: 4096,
+            nlink: 1,
+            dev: 1,
+            inode: 0,
+        }
+    }
+
+    fn recording(paths: &[&str], matching: usize, modified: u64) -> Candidate {
+        let files = paths.iter().map(|path| file(path, modified)).collect();
+        Candidate::new(files, matching, 0, "front".to_string())
+    }
+
+    fn paths(candidates: &[Candidate]) -> Vec<&Path> {
+        candidates.iter().map(Candidate::path).collect()
+    }
+
+    fn selection() -> Selection {
+        Selection {
+            filter_extensions: vec![OsString::from("mp4")],
+            case_sensitive_extensions: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            camera_depth: 1,
+            tiers: Vec::new(),
+            skip_dirs: Vec::new(),
+            walk_threads: 1,
+            index: None,
+            budget: None,
+            expire_before: None,
+            retain_from: None,
+            passed_over: HashSet::new(),
+        }
+    }
+
+    /// What a cleanup would remove to free `budget`, in order, and how many recordings
+    /// were too young to.
+    fn drain(mut cameras: Cameras, retain_from: u64, budget: Space) -> (Vec<PathBuf>, usize) {
+        let held_back = cameras.held_back + cameras.hold_back_from(retain_from);
+        let mut freed = Space::default();
+        let mut removed = Vec::new();
+        while !freed.covers(&budget)
+            && let Some(camera) = cameras.next_camera(false)
+        {
+            let candidate = cameras.pop_front(&camera).expect("next camera has files");
+            freed += candidate.space();
+            removed.push(candidate.path().to_path_buf());
+        }
+        (removed, held_back)
+    }
+
+    #[test]
+    fn matching_by_extension_or_include() {
+        let mut selection = selection();
+        selection.filter_extensions.push(OsString::from("jpg"));
+        assert_eq!(selection.matches(Path::new("front/clip.mp4")), Some(0));
+        assert_eq!(selection.matches(Path::new("front/clip.JPG")), Some(1));
+        assert_eq!(selection.matches(Path::new("front/clip.json")), None);
+        assert_eq!(selection.matches(Path::new("front/clipmp4")), None);
+        assert_eq!(selection.matches(Path::new("front/mp4")), None);
+
+        selection.case_sensitive_extensions = true;
+        assert_eq!(selection.matches(Path::new("front/clip.JPG")), None);
+
+        // extensions are ignored once there are includes
+        selection.include = vec!["*/*.json".parse().unwrap(), "front/**".parse().unwrap()];
+        assert_eq!(selection.matches(Path::new("front/clip.json")), Some(0));
+        assert_eq!(selection.matches(Path::new("front/clip.mp4")), Some(1));
+        assert_eq!(selection.matches(Path::new("back/clip.mp4")), None);
+    }
+
+    #[test]
+    fn stems_take_off_whole_extensions() {
+        let mut selection = selection();
+        selection.filter_extensions.push(OsString::from("mp4.part"));
+        selection.filter_extensions.push(OsString::from("jpg"));
+        for path in ["clip.mp4", "clip.mp4.part", "clip.JPG", "clip.json"] {
+            assert_eq!(
+                selection.stem(&Path::new("front").join(path)),
+                Path::new("front/clip")
+            );
+        }
+        assert_eq!(
+            selection.stem(Path::new("front/clip.v2.json")),
+            Path::new("front/clip.v2")
+        );
+    }
+
+    #[test]
+    fn fair_share_picks_the_camera_furthest_over_it() {
+        let mut cameras = Cameras::default();
+        for i in 0..3 {
+            let path = format!("front/clip{i}.mp4");
+            cameras.push("front".to_string(), recording(&[&path], 1, BASE + DAY + i));
+        }
+        cameras.push("back".to_string(), recording(&["back/clip.mp4"], 1, BASE));
+        // furthest over, but not in the lowest tier
+        for i in 0..5 {
+            let mut candidate = recording(&[&format!("side/clip{i}.mp4")], 1, BASE + i);
+            candidate.tier = 1;
+            cameras.push("side".to_string(), candidate);
+        }
+
+        assert_eq!(cameras.next_camera(false).as_deref(), Some("back"));
+        assert_eq!(cameras.next_camera(true).as_deref(), Some("front"));
+        // a quota is its camera's share, in place of an equal split
+        cameras.set_quota("front", 10 * 4096);
+        assert_eq!(cameras.next_camera(true).as_deref(), Some("back"));
+    }
+
+    #[test]
+    fn budget_keeps_only_the_oldest_that_cover_it() {
+        let budget = Space {
+            bytes: 2 * 4096,
+            inodes: 2,
+        };
+        let mut kept = Kept::new(Some(budget), Some(BASE + 60), None);
+        for i in [3, 0, 4, 2, 1] {
+            kept.push(recording(
+                &[&format!("front/clip{i}.mp4")],
+                1,
+                BASE + i * 60,
+            ));
+        }
+        let mut linked = recording(&["front/linked.mp4"], 1, BASE + 60);
+        linked.files[0].nlink = 2;
+        kept.push(linked);
+        assert!(kept.dropped);
+
+        let mut candidates = kept.into_candidates();
+        candidates.sort_unstable_by_key(|candidate| candidate.modified);
+        // clip0 is past max age, so it's kept on top of the budget; removing the hard
+        // linked one would free nothing
+        assert_eq!(
+            paths(&candidates),
+            ["front/clip0.mp4", "front/clip1.mp4", "front/clip2.mp4"].map(Path::new)
+        );
+    }
+
+    #[test]
+    fn bounded_selection_matches_unbounded() {
+        let directories = [PathBuf::from("/cams")];
+        let now = BASE + 10 * DAY;
+        let retain_from = now - 5 * DAY;
+        // the young ones sort first, being in the lowest tier, but can't be removed
+        let files: Vec<FileInfo> = (0..5)
+            .map(|i| file(&format!("/cams/back/young{i}.mp4"), now - i * 60))
+            .chain((0..5).map(|i| file(&format!("/cams/front/old{i}.mp4"), BASE + i * 60)))
+            .collect();
+        let find = |selection: &Selection| {
+            let mut finder = Finder::new(&directories, selection);
+            finder.add_dir(files.iter().cloned().map(Ok), &mut Failures::default());
+            finder.finish()
+        };
+        let mut selection = selection();
+        selection.tiers = vec!["back/**".parse().unwrap()];
+        let budget = Space {
+            bytes: 3 * 4096,
+            inodes: 3,
+        };
+
+        let unbounded = drain(find(&selection), retain_from, budget);
+        selection.budget = Some(budget);
+        selection.retain_from = Some(retain_from);
+        let cameras = find(&selection);
+        assert!(cameras.incomplete);
+        let bounded = drain(cameras, retain_from, budget);
+        assert_eq!(
+            unbounded.0,
+            [
+                "/cams/front/old0.mp4",
+                "/cams/front/old1.mp4",
+                "/cams/front/old2.mp4"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(unbounded.1, 5);
+        assert_eq!(bounded, unbounded);
+    }
+
+    #[test]
+    fn walking_in_parallel_finds_the_same_recordings() {
+        let root = std::env::temp_dir().join(format!("cam-tool-walk-{}", std::process::id()));
+        let mut modified = BASE;
+        for camera in ["front", "back", "side"] {
+            for day in 0..4 {
+                let dir = root.join(camera).join(format!("day{day}"));
+                fs::create_dir_all(&dir).unwrap();
+                for clip in 0..3 {
+                    for ext in ["mp4", "jpg"] {
+                        let file = fs::File::create(dir.join(format!("clip{clip}.{ext}"))).unwrap();
+                        file.set_modified(UNIX_EPOCH + Duration::from_secs(modified))
+                            .unwrap();
+                    }
+                    modified += 60;
+                }
+            }
+        }
+        let directories = [root.clone()];
+        let mut selection = selection();
+        selection.filter_extensions.push(OsString::from("jpg"));
+        let everything = Space {
+            bytes: u64::MAX,
+            inodes: u64::MAX,
+        };
+        let mut found = Vec::new();
+        for threads in [1, 4] {
+            selection.walk_threads = threads;
+            let mut failures = Failures::default();
+            let cameras = find_matching_files(&directories, &selection, &mut failures).unwrap();
+            assert_eq!(failures.count(), 0);
+            found.push(drain(cameras, u64::MAX, everything));
+        }
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(found[0].0.len(), 36);
+        assert_eq!(found[1], found[0]);
+    }
+
+    #[test]
+    fn thinning_leaves_videos_with_thumbnails_alone() {
+        let mut cameras = Cameras::default();
+        for i in 0..3 {
+            let modified = BASE + i * 60;
+            cameras.push(
+                "front".to_string(),
+                // named by the thumbnail, as with `--filter-extensions jpg mp4`
+                recording(
+                    &[&format!("front/clip{i}.jpg"), &format!("front/clip{i}.mp4")],
+                    2,
+                    modified,
+                ),
+            );
+            cameras.push(
+                "front".to_string(),
+                recording(
+                    &[
+                        &format!("front/snap{i}.jpg"),
+                        &format!("front/snap{i}.json"),
+                    ],
+                    1,
+                    modified,
+                ),
+            );
+        }
+        let rules = [ThinRule {
+            after: Duration::from_secs(DAY),
+            keep_one_per: Duration::from_secs(10 * 60),
+        }];
+        let extensions = [OsString::from("jpg"), OsString::from("jpeg")];
+        let thinned = cameras.take_thinned(BASE + 3 * DAY, &rules, |candidate| {
+            candidate.only_has_extensions(&extensions)
+        });
+        assert_eq!(
+            paths(&thinned),
+            [Path::new("front/snap1.jpg"), Path::new("front/snap2.jpg")]
+        );
+    }
+}
@@ -0,0 +1,649 @@
+use anyhow::{Context, Result, anyhow, bail};
+use log::{debug, info, warn};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, poll};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::fd::AsFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::candidates::{Cameras, Candidate, FileInfo, Recording, Selection, file_info};
+use crate::errors::Failures;
+use crate::pin;
+use crate::remove::Remover;
+use crate::service::{self, Stopped};
+use crate::usage::{group_by_filesystem, read_usage};
+use crate::{CleanupArgs, Outcome, check_quarantine, clean_filesystem, run_cleanup};
+
+/// What to hear about in every directory: files and directories arriving, being written,
+/// and going, and files' mtimes being set, as copying recordings in with them kept does.
+const WATCHING: AddWatchFlags = AddWatchFlags::IN_CREATE
+    .union(AddWatchFlags::IN_CLOSE_WRITE)
+    .union(AddWatchFlags::IN_ATTRIB)
+    .union(AddWatchFlags::IN_MOVED_TO)
+    .union(AddWatchFlags::IN_DELETE)
+    .union(AddWatchFlags::IN_MOVED_FROM)
+    .union(AddWatchFlags::IN_ONLYDIR)
+    .union(AddWatchFlags::IN_DONT_FOLLOW);
+
+/// How often to apply `--max-age`, `--camera-quota` and `--thin` without `--interval`, as
+/// recordings age past them without anything being written.
+const POLICY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to leave a filesystem where everything left was too young to delete before
+/// cleaning it up for writes again, as only time changes that.
+const FLOOR_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Keep cleaning up `args.directory` until asked to stop: every `interval`, plus up to
+/// `jitter`, or, without one, as soon as a write takes usage past the targets. Returns
+/// how the worst cleanup went.
+pub fn run(
+    args: &CleanupArgs,
+    remover: &mut Remover,
+    interval: Option<Duration>,
+    jitter: Duration,
+) -> Result<Outcome> {
+    args.validate()?;
+    if args.bounded_memory && interval.is_none() {
+        bail!("--bounded-memory needs --interval, as following the directories holds every file");
+    }
+    let filesystems = group_by_filesystem(&args.directory)?;
+    check_quarantine(&filesystems, remover)?;
+    service::stop_on_signals()?;
+    service::notify("READY=1");
+    let mut worst = Outcome::Done;
+    let result = match interval {
+        Some(interval) => every(args, &filesystems, remover, interval, jitter, &mut worst),
+        None => watch(args, &filesystems, remover, &mut worst),
+    };
+    match result {
+        Err(e) if !e.is::<Stopped>() => return Err(e),
+        _ => {}
+    }
+    service::notify("STOPPING=1");
+    info!("stopping");
+    remover.sync();
+    remover.failures().summarise();
+    Ok(worst)
+}
+
+/// Clean up like `violent-cleanup`, reading the directories again each time.
+fn every(
+    args: &CleanupArgs,
+    filesystems: &BTreeMap<u64, Vec<PathBuf>>,
+    remover: &mut Remover,
+    interval: Duration,
+    jitter: Duration,
+    worst: &mut Outcome,
+) -> Result<()> {
+    loop {
+        remover.set_busy(args.busy.check()?);
+        let outcome = run_cleanup(args, remover)?;
+        debug!("{outcome:?}");
+        *worst = (*worst).max(outcome);
+        report(args, filesystems, remover)?;
+        let wait = service::jittered(interval, jitter);
+        info!("cleaning up again in {}", humantime::format_duration(wait));
+        if !service::sleep(wait) {
+            return Ok(());
+        }
+    }
+}
+
+/// Read the directories once, then follow them with inotify, cleaning up as soon as a
+/// write takes usage past the targets, and every `POLICY_INTERVAL` if there are policies.
+fn watch(
+    args: &CleanupArgs,
+    filesystems: &BTreeMap<u64, Vec<PathBuf>>,
+    remover: &mut Remover,
+    worst: &mut Outcome,
+) -> Result<()> {
+    let targets = args.targets();
+    let roots: Vec<PathBuf> = filesystems.values().flatten().cloned().collect();
+    let skip_dir = remover.quarantine().map(|q| q.root().to_path_buf());
+    let mut tree = Tree::new(args.selection(), roots.clone(), skip_dir)?;
+    service::notify("STATUS=reading the directories");
+    for root in &roots {
+        tree.scan(root, remover.failures_mut())?;
+    }
+    info!(
+        "watching {} directories, holding {} files in {} recordings",
+        tree.watched.len(),
+        tree.dirs.values().map(HashMap::len).sum::<usize>(),
+        tree.recordings.len()
+    );
+
+    // filesystems that stopped at `--min-retention`, and when to clean them up again
+    let mut at_floor = HashMap::new();
+    // the first cleanup catches up, including with `--max-age` and the like
+    for (dev, directories) in filesystems {
+        let outcome = clean(args, directories, &tree, remover)?;
+        back_off(&mut at_floor, *dev, outcome);
+        *worst = (*worst).max(outcome);
+    }
+    report(args, filesystems, remover)?;
+    let mut policies_due = Instant::now() + POLICY_INTERVAL;
+    loop {
+        service::keep_alive();
+        if service::stopping() {
+            return Ok(());
+        }
+        let mut ready = [PollFd::new(tree.inotify.as_fd(), PollFlags::POLLIN)];
+        let events = match poll(&mut ready, service::TICK.as_millis() as u16) {
+            Ok(0) => Vec::new(),
+            Ok(_) => match tree.inotify.read_events() {
+                Ok(events) => events,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(anyhow!(e).context("reading inotify events")),
+            },
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(anyhow!(e).context("waiting for inotify events")),
+        };
+        let mut written = Vec::new();
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                warn!("missed some changes, reading every directory again");
+                tree.rescan(remover.failures_mut())?;
+                written.extend(roots.iter().cloned());
+            } else if let Some(dir) = tree.apply(event, remover.failures_mut())? {
+                written.push(dir);
+            }
+        }
+        // recordings age past the policies with nothing written, so they're applied on a timer
+        let policies = args.has_policies() && Instant::now() >= policies_due;
+        if policies {
+            policies_due = Instant::now() + POLICY_INTERVAL;
+        }
+        let mut cleaned = false;
+        for (dev, directories) in filesystems {
+            if policies {
+                let outcome = clean(args, directories, &tree, remover)?;
+                back_off(&mut at_floor, *dev, outcome);
+                *worst = (*worst).max(outcome);
+                cleaned = true;
+                continue;
+            }
+            if !written.iter().any(|dir| below(directories, dir))
+                || at_floor
+                    .get(dev)
+                    .is_some_and(|until| Instant::now() < *until)
+            {
+                continue;
+            }
+            let mut usage = read_usage(&directories[0], args.df_compatible)?;
+            if args.max_size.is_some() {
+                usage.tree_bytes = Some(tree.bytes_below(directories));
+            }
+            if usage.needs_cleanup(&targets) {
+                let outcome = clean(args, directories, &tree, remover)?;
+                back_off(&mut at_floor, *dev, outcome);
+                *worst = (*worst).max(outcome);
+                cleaned = true;
+            }
+        }
+        if cleaned {
+            report(args, filesystems, remover)?;
+        }
+    }
+}
+
+/// Clean up `directories` from what's in the tree, rather than reading them again.
+fn clean(
+    args: &CleanupArgs,
+    directories: &[PathBuf],
+    tree: &Tree,
+    remover: &mut Remover,
+) -> Result<Outcome> {
+    remover.set_busy(args.busy.check()?);
+    let find = |selection: &Selection, _: &mut Failures| Ok(tree.cameras(directories, selection));
+    let outcome = clean_filesystem(args, directories, remover, find)?;
+    remover.sync();
+    debug!("{directories:?}: {outcome:?}");
+    Ok(outcome)
+}
+
+/// Leave the filesystem `dev` alone for `FLOOR_BACKOFF` if cleaning it up stopped at
+/// `--min-retention`, rather than trying again, and failing again, on every write.
+fn back_off(at_floor: &mut HashMap<u64, Instant>, dev: u64, outcome: Outcome) {
+    if outcome == Outcome::RetentionFloor {
+        info!(
+            "not cleaning up for writes again for {}",
+            humantime::format_duration(FLOOR_BACKOFF)
+        );
+        at_floor.insert(dev, Instant::now() + FLOOR_BACKOFF);
+    } else {
+        at_floor.remove(&dev);
+    }
+}
+
+/// Tell systemd the usage of each filesystem, and roughly what the last cleanup freed,
+/// and log the errors with files since the last report, starting the count again.
+fn report(
+    args: &CleanupArgs,
+    filesystems: &BTreeMap<u64, Vec<PathBuf>>,
+    remover: &mut Remover,
+) -> Result<()> {
+    let mut usages = Vec::with_capacity(filesystems.len());
+    for directories in filesystems.values() {
+        let usage = read_usage(&directories[0], args.df_compatible)?;
+        usages.push(format!("{directories:?}: {usage}"));
+    }
+    let freed = remover.take_freed();
+    let failures = remover.take_failures();
+    failures.summarise();
+    service::notify(&format!(
+        "STATUS={}; last freed {freed}, with {} errors with files",
+        usages.join("; "),
+        failures.count()
+    ));
+    Ok(())
+}
+
+fn below(directories: &[PathBuf], path: &Path) -> bool {
+    directories.iter().any(|root| path.starts_with(root))
+}
+
+/// Where a recording is in `Tree::recordings`: its `age_order`.
+type AgeOrder = (usize, u64, PathBuf);
+
+/// Every file below the directories, and the recordings they make up, kept up to date by
+/// inotify a recording at a time, so cleaning up doesn't mean sorting every file out again.
+struct Tree {
+    inotify: Inotify,
+    /// what makes up a recording, relative to `roots`
+    selection: Selection,
+    roots: Vec<PathBuf>,
+    /// not watched at all, like the quarantine
+    skip_dir: Option<PathBuf>,
+    watched: HashMap<WatchDescriptor, PathBuf>,
+    dirs: HashMap<PathBuf, HashMap<OsString, FileInfo>>,
+    /// the names of the files sharing each stem, other than pin sidecars
+    stems: HashMap<PathBuf, HashSet<OsString>>,
+    /// every recording, lowest tier then oldest first
+    recordings: BTreeMap<AgeOrder, Candidate>,
+    /// where the recording of each stem is in `recordings`, or `None` if it's pinned
+    placed: HashMap<PathBuf, Option<AgeOrder>>,
+}
+
+impl Tree {
+    fn new(selection: Selection, roots: Vec<PathBuf>, skip_dir: Option<PathBuf>) -> Result<Tree> {
+        Ok(Tree {
+            inotify: Inotify::init(InitFlags::IN_CLOEXEC).context("starting inotify")?,
+            selection,
+            roots,
+            skip_dir,
+            watched: HashMap::new(),
+            dirs: HashMap::new(),
+            stems: HashMap::new(),
+            recordings: BTreeMap::new(),
+            placed: HashMap::new(),
+        })
+    }
+
+    /// Watch `dir` and everything below it, reading each directory only once it's
+    /// watched, so nothing added in between is missed.
+    fn scan(&mut self, dir: &Path, failures: &mut Failures) -> Result<()> {
+        if self.skip_dir.as_deref() == Some(dir) {
+            return Ok(());
+        }
+        service::keep_alive();
+        match self.inotify.add_watch(dir, WATCHING) {
+            Ok(wd) => {
+                self.watched.insert(wd, dir.to_path_buf());
+            }
+            // gone again already
+            Err(Errno::ENOENT | Errno::ENOTDIR) => return Ok(()),
+            Err(Errno::ENOSPC) => {
+                return Err(anyhow!(io::Error::from(Errno::ENOSPC)).context(format!(
+                    "watching {dir:?}: too many directories, raise fs.inotify.max_user_watches"
+                )));
+            }
+            Err(e) => return Err(anyhow!(e).context(format!("watching {dir:?}"))),
+        }
+
+        let entries = match fs::read_dir(dir).with_context(|| anyhow!("reading {dir:?}")) {
+            Ok(entries) => entries,
+            Err(e) => {
+                failures.record(&e);
+                return Ok(());
+            }
+        };
+        self.dirs.entry(dir.to_path_buf()).or_default();
+        let mut stems = HashSet::new();
+        let mut subdirs = Vec::new();
+        for entry in entries {
+            let result = entry
+                .and_then(|entry| Ok((entry.file_name(), entry.file_type()?)))
+                .with_context(|| anyhow!("reading {dir:?}"));
+            let (name, file_type) = match result {
+                Ok(entry) => entry,
+                Err(e) => {
+                    failures.record(&e);
+                    continue;
+                }
+            };
+            let path = dir.join(&name);
+            if file_type.is_dir() {
+                subdirs.push(path);
+            } else if file_type.is_file() {
+                match file_info(&path) {
+                    Ok(file) => {
+                        stems.insert(self.add_file(dir, name, file));
+                    }
+                    Err(e) => failures.record(&e),
+                }
+            }
+        }
+        for stem in stems {
+            self.refresh(&stem);
+        }
+        for subdir in subdirs {
+            self.scan(&subdir, failures)?;
+        }
+        Ok(())
+    }
+
+    /// Forget every file and read `roots` again, after events were lost.
+    fn rescan(&mut self, failures: &mut Failures) -> Result<()> {
+        self.dirs.clear();
+        self.stems.clear();
+        self.recordings.clear();
+        self.placed.clear();
+        for root in self.roots.clone() {
+            self.scan(&root, failures)?;
+        }
+        Ok(())
+    }
+
+    /// Bring the tree up to date with `event`, returning the directory if it was a file
+    /// being written to it, or arriving in it.
+    fn apply(&mut self, event: InotifyEvent, failures: &mut Failures) -> Result<Option<PathBuf>> {
+        if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+            self.watched.remove(&event.wd);
+            return Ok(None);
+        }
+        let (Some(dir), Some(name)) = (self.watched.get(&event.wd), event.name) else {
+            return Ok(None);
+        };
+        let dir = dir.clone();
+        let path = dir.join(&name);
+        let arrived = event
+            .mask
+            .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO);
+        let left = event
+            .mask
+            .intersects(AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM);
+
+        if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+            if arrived {
+                // moved in with files already in it, or created, and quickly filled
+                self.scan(&path, failures)?;
+                return Ok(Some(path));
+            }
+            if left {
+                self.forget(&path, event.mask.contains(AddWatchFlags::IN_MOVED_FROM));
+            }
+            return Ok(None);
+        }
+
+        if left {
+            let stem = self.remove_file(&dir, &name);
+            self.refresh(&stem);
+            return Ok(None);
+        }
+        let stem = match file_info(&path) {
+            Ok(file) => self.add_file(&dir, name, file),
+            Err(e) => {
+                // gone again already, or not a regular file
+                debug!("not holding {path:?}: {e:#}");
+                self.remove_file(&dir, &name)
+            }
+        };
+        self.refresh(&stem);
+        Ok(Some(dir))
+    }
+
+    /// Hold `file`, returning the stem of the recording it's part of, or pins.
+    fn add_file(&mut self, dir: &Path, name: OsString, file: FileInfo) -> PathBuf {
+        let stem = self.stem_of(&file.path);
+        if !is_sidecar(&file.path) {
+            self.stems
+                .entry(stem.clone())
+                .or_default()
+                .insert(name.clone());
+        }
+        self.dirs
+            .entry(dir.to_path_buf())
+            .or_default()
+            .insert(name, file);
+        stem
+    }
+
+    /// Stop holding the file `name` in `dir`, returning the stem of the recording it was
+    /// part of, or pinned.
+    fn remove_file(&mut self, dir: &Path, name: &OsStr) -> PathBuf {
+        if let Some(files) = self.dirs.get_mut(dir) {
+            files.remove(name);
+        }
+        let stem = self.stem_of(&dir.join(name));
+        if let Some(names) = self.stems.get_mut(&stem) {
+            names.remove(name);
+            if names.is_empty() {
+                self.stems.remove(&stem);
+            }
+        }
+        stem
+    }
+
+    fn stem_of(&self, path: &Path) -> PathBuf {
+        if is_sidecar(path) {
+            self.selection.stem(&path.with_extension(""))
+        } else {
+            self.selection.stem(path)
+        }
+    }
+
+    /// Sort out the recording of the files sharing `stem` again, after one of them, or
+    /// one of their pin sidecars, changed. Only its files are looked at, so only its pins
+    /// are read again.
+    fn refresh(&mut self, stem: &Path) {
+        if let Some(Some(age_order)) = self.placed.remove(stem) {
+            self.recordings.remove(&age_order);
+        }
+        let (Some(files), Some(names)) = (
+            stem.parent().and_then(|dir| self.dirs.get(dir)),
+            self.stems.get(stem),
+        ) else {
+            return;
+        };
+        let mut pin_sidecars = HashSet::new();
+        let mut members = Vec::with_capacity(names.len());
+        for file in names.iter().filter_map(|name| files.get(name)) {
+            let sidecar = pin::sidecar_of(&file.path);
+            if sidecar
+                .file_name()
+                .is_some_and(|name| files.contains_key(name))
+            {
+                pin_sidecars.insert(sidecar);
+            }
+            members.push(file.clone());
+        }
+        let placed = match self
+            .selection
+            .recording(&self.roots, members, &pin_sidecars)
+        {
+            Recording::Unmatched => return,
+            Recording::Pinned => None,
+            Recording::Found(candidate) => {
+                let (tier, modified, path) = candidate.age_order();
+                let age_order = (tier, modified, path.to_path_buf());
+                self.recordings.insert(age_order.clone(), candidate);
+                Some(age_order)
+            }
+        };
+        self.placed.insert(stem.to_path_buf(), placed);
+    }
+
+    /// Stop tracking the directory at `path`, and everything below it. Watches on
+    /// directories moved away have to be removed, or they'd carry on reporting under
+    /// their old paths; those on deleted ones go by themselves.
+    fn forget(&mut self, path: &Path, moved: bool) {
+        self.dirs.retain(|dir, _| !dir.starts_with(path));
+        self.stems.retain(|stem, _| !stem.starts_with(path));
+        let recordings = &mut self.recordings;
+        self.placed.retain(|stem, placed| {
+            if !stem.starts_with(path) {
+                return true;
+            }
+            if let Some(age_order) = placed {
+                recordings.remove(age_order);
+            }
+            false
+        });
+        let gone: Vec<WatchDescriptor> = self
+            .watched
+            .iter()
+            .filter(|(_, dir)| dir.starts_with(path))
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in gone {
+            self.watched.remove(&wd);
+            if moved && let Err(e) = self.inotify.rm_watch(wd) {
+                debug!("not unwatching below {path:?}: {e}");
+            }
+        }
+    }
+
+    /// What `find_matching_files` would find in `directories`, from memory.
+    fn cameras(&self, directories: &[PathBuf], selection: &Selection) -> Cameras {
+        let pinned = self
+            .placed
+            .iter()
+            .filter(|(stem, placed)| placed.is_none() && below(directories, stem))
+            .count();
+        if pinned > 0 {
+            info!("skipping {pinned} pinned recordings");
+        }
+        let candidates = self.recordings.values().filter(|candidate| {
+            below(directories, candidate.path())
+                && !selection.passed_over.contains(candidate.path())
+        });
+        Cameras::from_ordered(self.bytes_below(directories), candidates.cloned())
+    }
+
+    /// Allocated for every file below `directories`, like `du`.
+    fn bytes_below(&self, directories: &[PathBuf]) -> u64 {
+        let mut hard_linked = HashSet::new();
+        self.dirs
+            .iter()
+            .filter(|(dir, _)| below(directories, dir))
+            .flat_map(|(_, files)| files.values())
+            .filter(|file| file.nlink == 1 || hard_linked.insert(file.inode))
+            .map(|file| file.allocated)
+            .sum()
+    }
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "keep")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::candidates::find_matching_files;
+
+    fn selection() -> Selection {
+        Selection {
+            filter_extensions: vec![OsString::from("mp4")],
+            case_sensitive_extensions: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            camera_depth: 1,
+            tiers: vec!["back/**".parse().unwrap()],
+            skip_dirs: Vec::new(),
+            walk_threads: 1,
+            index: None,
+            budget: None,
+            expire_before: None,
+            retain_from: None,
+            passed_over: HashSet::new(),
+        }
+    }
+
+    /// The paths of the recordings, in the order they'd be removed.
+    fn order(mut cameras: Cameras) -> Vec<PathBuf> {
+        let mut paths: Vec<Vec<PathBuf>> = Vec::new();
+        while let Some(camera) = cameras.next_camera(false) {
+            let candidate = cameras.pop_front(&camera).expect("next camera has files");
+            paths.push(candidate.files.iter().map(|f| f.path.clone()).collect());
+        }
+        paths.concat()
+    }
+
+    fn write(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"recording").unwrap();
+    }
+
+    #[test]
+    fn following_changes_finds_what_reading_again_would() {
+        let root = std::env::temp_dir().join(format!("cam-tool-tree-{}", std::process::id()));
+        for path in [
+            "front/clip0.mp4",
+            "front/clip0.jpg",
+            "front/clip1.mp4",
+            "front/notes.txt",
+            "back/day0/clip0.mp4",
+            "back/day0/clip1.mp4",
+            "back/day0/clip1.mp4.keep",
+        ] {
+            write(&root.join(path));
+        }
+        let roots = vec![root.clone()];
+        let mut tree = Tree::new(selection(), roots.clone(), None).unwrap();
+        let selection = selection();
+        let mut failures = Failures::default();
+        tree.scan(&root, &mut failures).unwrap();
+        let found = |failures: &mut Failures| {
+            order(find_matching_files(&roots, &selection, failures).unwrap())
+        };
+        assert_eq!(
+            order(tree.cameras(&roots, &selection)),
+            found(&mut failures)
+        );
+
+        fs::remove_file(root.join("front/clip0.mp4")).unwrap();
+        fs::remove_file(root.join("back/day0/clip1.mp4.keep")).unwrap();
+        write(&root.join("front/clip1.mp4.keep"));
+        write(&root.join("front/clip2.mp4"));
+        write(&root.join("back/day1/clip0.mp4"));
+        fs::rename(root.join("back/day0"), root.join("day0")).unwrap();
+        loop {
+            let mut ready = [PollFd::new(tree.inotify.as_fd(), PollFlags::POLLIN)];
+            if poll(&mut ready, 0u16).unwrap() == 0 {
+                break;
+            }
+            for event in tree.inotify.read_events().unwrap() {
+                tree.apply(event, &mut failures).unwrap();
+            }
+        }
+        let mut followed = order(tree.cameras(&roots, &selection));
+        let expected = found(&mut failures);
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(followed, expected);
+        followed.sort();
+        let left = [
+            "back/day1/clip0.mp4",
+            "day0/clip0.mp4",
+            "day0/clip1.mp4",
+            "front/clip2.mp4",
+        ];
+        assert_eq!(followed, left.map(|path| root.join(path)));
+        assert_eq!(failures.count(), 0);
+    }
+}
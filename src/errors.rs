@@ -0,0 +1,39 @@
+use log::{error, warn};
+use std::collections::BTreeMap;
+use std::io;
+
+/// Errors with single files, which are logged and counted rather than stopping the run,
+/// as giving up would leave the disk full.
+#[derive(Debug, Default)]
+pub struct Failures {
+    by_kind: BTreeMap<String, usize>,
+}
+
+impl Failures {
+    /// Note an error with a file, like removing one a recorder has already rotated away.
+    pub fn record(&mut self, e: &anyhow::Error) {
+        warn!("{e:#}, carrying on");
+        let kind = match e.chain().find_map(|e| e.downcast_ref::<io::Error>()) {
+            Some(e) => e.kind().to_string(),
+            None => "other error".to_string(),
+        };
+        *self.by_kind.entry(kind).or_default() += 1;
+    }
+
+    pub fn count(&self) -> usize {
+        self.by_kind.values().sum()
+    }
+
+    /// Log how many of each kind of error there were, if there were any.
+    pub fn summarise(&self) {
+        if self.by_kind.is_empty() {
+            return;
+        }
+        let kinds: Vec<String> = self
+            .by_kind
+            .iter()
+            .map(|(kind, count)| format!("{count} {kind}"))
+            .collect();
+        error!("errors with files: {}", kinds.join(", "));
+    }
+}
@@ -0,0 +1,268 @@
+use anyhow::{Context, Result, anyhow};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::candidates::FileInfo;
+
+/// Directory mtimes can be this coarse, like on FAT, so a directory read within this
+/// long of its mtime could change again without its mtime changing.
+const MTIME_GRANULARITY: i64 = 2;
+
+/// Files modified within this long of being indexed may still have been being written,
+/// which doesn't change their directory's mtime, so they're looked at again.
+const STILL_WRITING: u64 = 60 * 60;
+
+/// What was in every directory walked when it was last read, so later walks only have to
+/// read the directories that have changed since: adding, removing or renaming a file
+/// changes its directory's mtime.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    dirs: HashMap<PathBuf, IndexedDir>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedDir {
+    mtime: i64,
+    mtime_nsec: i64,
+    /// unix time the directory was read
+    indexed_at: u64,
+    dev: u64,
+    subdirs: Vec<PathBuf>,
+    files: Vec<IndexedFile>,
+}
+
+/// Saved as a row of its fields, rather than with their names, which would more than
+/// double the size of the index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "FileRow", into = "FileRow")]
+struct IndexedFile {
+    name: PathBuf,
+    modified: u64,
+    size: u64,
+    allocated: u64,
+    nlink: u64,
+    inode: u64,
+}
+
+type FileRow = (PathBuf, u64, u64, u64, u64, u64);
+
+impl From<FileRow> for IndexedFile {
+    fn from((name, modified, size, allocated, nlink, inode): FileRow) -> IndexedFile {
+        IndexedFile {
+            name,
+            modified,
+            size,
+            allocated,
+            nlink,
+            inode,
+        }
+    }
+}
+
+impl From<IndexedFile> for FileRow {
+    fn from(file: IndexedFile) -> FileRow {
+        (
+            file.name,
+            file.modified,
+            file.size,
+            file.allocated,
+            file.nlink,
+            file.inode,
+        )
+    }
+}
+
+impl Index {
+    /// The index at `path`, or an empty one if there isn't one yet, or it can't be read.
+    pub fn load(path: &Path) -> Index {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                info!("not using the index at {path:?}, starting a new one: {e}");
+                return Index::default();
+            }
+        };
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("can't parse the index at {path:?}, starting a new one: {e}");
+                Index::default()
+            }
+        }
+    }
+
+    /// Replace `path` with this index, all at once.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        let file =
+            fs::File::create(&temporary).with_context(|| anyhow!("creating {temporary:?}"))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&temporary, path).with_context(|| anyhow!("replacing {path:?}"))?;
+        Ok(())
+    }
+
+    /// What was in `dir` when it was indexed, if it hasn't changed since.
+    pub fn get(&self, dir: &Path, metadata: &fs::Metadata) -> Option<&IndexedDir> {
+        self.dirs.get(dir).filter(|indexed| {
+            indexed.mtime == metadata.mtime()
+                && indexed.mtime_nsec == metadata.mtime_nsec()
+                && indexed.dev == metadata.dev()
+                && (indexed.indexed_at as i64) >= indexed.mtime + MTIME_GRANULARITY
+        })
+    }
+
+    /// Forget everything below `directories`, which have just been walked, in favour of
+    /// `walked`.
+    pub fn update(&mut self, directories: &[PathBuf], walked: Vec<(PathBuf, IndexedDir)>) {
+        self.dirs
+            .retain(|dir, _| !directories.iter().any(|root| dir.starts_with(root)));
+        self.dirs.extend(walked);
+    }
+}
+
+impl IndexedDir {
+    pub fn new(metadata: &fs::Metadata, indexed_at: u64) -> IndexedDir {
+        IndexedDir {
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            indexed_at,
+            dev: metadata.dev(),
+            subdirs: Vec::new(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn add_subdir(&mut self, path: &Path) {
+        if let Some(name) = path.file_name() {
+            self.subdirs.push(PathBuf::from(name));
+        }
+    }
+
+    pub fn add_file(&mut self, file: &FileInfo) {
+        if let Some(name) = file.path.file_name() {
+            self.files.push(IndexedFile {
+                name: PathBuf::from(name),
+                modified: file.modified,
+                size: file.size,
+                allocated: file.allocated,
+                nlink: file.nlink,
+                inode: file.inode,
+            });
+        }
+    }
+
+    pub fn subdirs<'d>(&'d self, dir: &'d Path) -> impl Iterator<Item = PathBuf> + 'd {
+        self.subdirs.iter().map(|name| dir.join(name))
+    }
+
+    /// The files in `dir`, or the paths of those that need to be looked at again.
+    pub fn files<'d>(
+        &'d self,
+        dir: &'d Path,
+    ) -> impl Iterator<Item = Result<FileInfo, PathBuf>> + 'd {
+        self.files.iter().map(|file| {
+            let path = dir.join(&file.name);
+            if file.modified + STILL_WRITING >= self.indexed_at {
+                return Err(path);
+            }
+            Ok(FileInfo {
+                path,
+                modified: file.modified,
+                size: file.size,
+                allocated: file.allocated,
+                nlink: file.nlink,
+                dev: self.dev,
+                inode: file.inode,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Set `dir`'s mtime, returning its metadata after.
+    fn set_mtime(dir: &Path, mtime: u64) -> fs::Metadata {
+        let file = fs::File::open(dir).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+        file.metadata().unwrap()
+    }
+
+    #[test]
+    fn only_unchanged_directories_are_reused() {
+        let dir = std::env::temp_dir().join(format!("cam-tool-index-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mtime = 1_000_000_000;
+        let metadata = set_mtime(&dir, mtime);
+
+        let mut index = Index::default();
+        let indexed_at = mtime + MTIME_GRANULARITY as u64;
+        index.update(
+            std::slice::from_ref(&dir),
+            vec![(dir.clone(), IndexedDir::new(&metadata, indexed_at))],
+        );
+        assert!(index.get(&dir, &metadata).is_some());
+        assert!(index.get(&dir.join("other"), &metadata).is_none());
+
+        // read so soon after it changed that it could have changed again since
+        index.update(
+            std::slice::from_ref(&dir),
+            vec![(dir.clone(), IndexedDir::new(&metadata, indexed_at - 1))],
+        );
+        assert!(index.get(&dir, &metadata).is_none());
+
+        index.update(
+            std::slice::from_ref(&dir),
+            vec![(dir.clone(), IndexedDir::new(&metadata, indexed_at))],
+        );
+        let changed = set_mtime(&dir, mtime + 60);
+        fs::remove_dir(&dir).unwrap();
+        assert!(index.get(&dir, &changed).is_none());
+    }
+
+    #[test]
+    fn files_are_saved_without_field_names() {
+        let dir = std::env::temp_dir().join(format!("cam-tool-rows-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let metadata = fs::metadata(&dir).unwrap();
+        let mut indexed = IndexedDir::new(&metadata, metadata.mtime() as u64 + STILL_WRITING + 60);
+        indexed.add_file(&FileInfo {
+            path: dir.join("clip.mp4"),
+            modified: metadata.mtime() as u64,
+            size: 1000,
+            allocated: 4096,
+            nlink: 1,
+            dev: metadata.dev(),
+            inode: 7,
+        });
+        let mut index = Index::default();
+        index.update(std::slice::from_ref(&dir), vec![(dir.clone(), indexed)]);
+        let path = dir.join("index.json");
+        index.save(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        let loaded = Index::load(&path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(saved.contains(r#"[["clip.mp4","#), "{saved}");
+        let files: Vec<FileInfo> = loaded.dirs[&dir].files(&dir).map(Result::unwrap).collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, dir.join("clip.mp4"));
+        assert_eq!(
+            (files[0].size, files[0].allocated, files[0].inode),
+            (1000, 4096, 7)
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::candidates::FileInfo;
+use crate::mb;
+use crate::usage::{Usage, read_usage};
+
+/// Every file deleted, one JSON object per line, only ever appended to, so it can still
+/// say why a recording is gone long after the logs have rotated away.
+pub struct Journal {
+    file: fs::File,
+    df_compatible: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// unix time of deletion
+    pub timestamp: u64,
+    /// where the file was, even if it was quarantined or purged from quarantine
+    pub path: PathBuf,
+    pub camera: String,
+    pub size: u64,
+    pub mtime: u64,
+    pub inode: u64,
+    /// why it was deleted
+    pub policy: String,
+    /// where it went, if it was moved into, or purged from, a quarantine
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined: Option<PathBuf>,
+    /// of the filesystem, around the deletion of the whole recording
+    pub usage_before: Usage,
+    pub usage_after: Usage,
+}
+
+impl Journal {
+    pub fn open(path: &Path, df_compatible: bool) -> Result<Journal> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| anyhow!("opening {path:?}"))?;
+        Ok(Journal {
+            file,
+            df_compatible,
+        })
+    }
+
+    /// The usage to record for deleting `path`.
+    pub fn usage(&self, path: &Path) -> Result<Usage> {
+        read_usage(path.parent().unwrap_or(path), self.df_compatible)
+    }
+
+    /// Append `entries` and sync them, so they're on disk before anything else is deleted.
+    pub fn record(&mut self, entries: &[JournalEntry]) -> Result<()> {
+        let mut lines = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        self.file.write_all(&lines)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Everything in the journal at `path`, oldest first. A line that can't be parsed, like
+    /// one cut short by a crash, is skipped with a warning.
+    pub fn read(path: &Path) -> Result<Vec<JournalEntry>> {
+        let file = fs::File::open(path).with_context(|| anyhow!("opening {path:?}"))?;
+        let mut entries = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| anyhow!("reading {path:?}"))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => warn!("skipping line {} of {path:?}: {e}", number + 1),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl JournalEntry {
+    pub fn new(
+        timestamp: u64,
+        file: &FileInfo,
+        camera: &str,
+        policy: &str,
+        usage_before: Usage,
+        usage_after: Usage,
+    ) -> JournalEntry {
+        JournalEntry {
+            timestamp,
+            path: file.path.clone(),
+            camera: camera.to_string(),
+            size: file.size,
+            mtime: file.modified,
+            inode: file.inode,
+            policy: policy.to_string(),
+            quarantined: None,
+            usage_before,
+            usage_after,
+        }
+    }
+}
+
+impl fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |secs| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs));
+        write!(
+            f,
+            "{} {:?} ({:.1} MB, modified {}",
+            time(self.timestamp),
+            self.path,
+            mb(self.size),
+            time(self.mtime)
+        )?;
+        if !self.camera.is_empty() {
+            write!(f, ", camera {}", self.camera)?;
+        }
+        if let Some(quarantined) = &self.quarantined {
+            write!(f, ", quarantined at {quarantined:?}")?;
+        }
+        write!(
+            f,
+            "): {}; before: {}, after: {}",
+            self.policy, self.usage_before, self.usage_after
+        )
+    }
+}
@@ -1,11 +1,39 @@
-use anyhow::{Context, Result, anyhow};
-use clap::{Parser, Subcommand};
-use log::{LevelFilter, info};
-use std::collections::VecDeque;
+mod busy;
+mod candidates;
+mod daemon;
+mod errors;
+mod index;
+mod journal;
+mod pattern;
+mod pin;
+mod plan;
+mod quarantine;
+mod remove;
+mod service;
+mod usage;
+mod walk;
+
+use anyhow::{Context, Result, anyhow, bail};
+use busy::BusyCheck;
+use candidates::{
+    Cameras, Candidate, Selection, ThinRule, camera_of, find_matching_files, relative_to,
+};
+use clap::{ArgGroup, Parser, Subcommand};
+use errors::Failures;
+use journal::{Journal, JournalEntry};
+use log::{LevelFilter, debug, error, info, warn};
+use pattern::Pattern;
+use plan::Plan;
+use quarantine::Quarantine;
+use remove::{Action, Durability, Remover};
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsStr;
 use std::ffi::OsString;
-use std::fs;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use usage::{Space, Targets, Usage, compute_to_free, group_by_filesystem, read_usage};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -16,22 +44,296 @@ struct Args {
 
 #[derive(Subcommand, Debug)]
 enum Command {
+    /// delete recordings until the targets are met. Errors with single files are counted
+    /// rather than stopping the cleanup; if any stop it reaching the targets, it exits with
+    /// status 4, or 5 if nothing could be deleted at all
     ViolentCleanup {
-        #[arg(short, long)]
-        directory: PathBuf,
+        #[command(flatten)]
+        cleanup: Box<CleanupArgs>,
 
-        #[arg(short, long, default_values=[OsStr::new("mp4"), OsStr::new("jpg")])]
-        filter_extensions: Vec<OsString>,
+        #[arg(long)]
+        actually_rm: bool,
+    },
 
-        #[arg(short, long)]
-        target_use_percentage: u8,
+    /// stay running, and clean up as soon as a file written takes usage past the high
+    /// watermark, or any other target, rather than waiting for cron. The directories are
+    /// read once, and then followed with inotify, holding every file, so `--index` and
+    /// `--walk-threads` aren't used, and `--bounded-memory` can't be, without `--interval`,
+    /// and `--max-age`, `--camera-quota` and `--thin` are applied every minute. Speaks the
+    /// `sd_notify` protocol, to run as a `Type=notify` unit, with `WatchdogSec=` if wanted,
+    /// and stops at the next file on SIGTERM, exiting as the worst cleanup would have
+    Daemon {
+        #[command(flatten)]
+        cleanup: Box<CleanupArgs>,
 
         #[arg(long)]
         actually_rm: bool,
+
+        /// rather than following the directories with inotify, clean up every INTERVAL
+        /// (e.g. `10m`), reading them again each time, as from cron; inotify doesn't see
+        /// changes made on other machines to network filesystems
+        #[arg(long, value_parser = humantime::parse_duration)]
+        interval: Option<Duration>,
+
+        /// wait up to this much longer than `--interval` each time (e.g. `1m`), picked at
+        /// random, so many machines sharing storage don't all clean up at once
+        #[arg(long, requires = "interval", value_parser = humantime::parse_duration)]
+        jitter: Option<Duration>,
+    },
+
+    /// work out what a cleanup would delete, and write it to a file to review and `apply`
+    Plan {
+        #[command(flatten)]
+        cleanup: Box<CleanupArgs>,
+
+        /// where to write the plan, as JSON
+        #[arg(short, long)]
+        output: PathBuf,
     },
+
+    /// delete what a `plan` listed, skipping anything that has changed since
+    Apply {
+        plan: PathBuf,
+
+        /// leave files matching any of these globs (or `re:` regexes) on the path below
+        /// `--directory`, and the rest of their recordings, even though they were planned
+        #[arg(long)]
+        exclude: Vec<Pattern>,
+
+        #[command(flatten)]
+        busy: BusyArgs,
+
+        #[command(flatten)]
+        quarantine: QuarantineArgs,
+
+        /// append what's deleted to this file, as JSON lines
+        #[arg(long)]
+        journal: Option<PathBuf>,
+
+        #[arg(long, value_enum, default_value_t = Durability::PerFile)]
+        durability: Durability,
+    },
+
+    /// show what was deleted, and why, from a `--journal`
+    Journal {
+        journal: PathBuf,
+
+        /// only deletions from this time on, as a UTC timestamp (e.g. `2024-05-01 12:00:00`)
+        /// or a duration ago (e.g. `30d`)
+        #[arg(long, value_parser = parse_time)]
+        since: Option<u64>,
+
+        /// only deletions up to this time, like `--since`
+        #[arg(long, value_parser = parse_time)]
+        until: Option<u64>,
+
+        /// only deletions from this camera; repeat for several
+        #[arg(long)]
+        camera: Vec<String>,
+
+        /// print the matching entries as they're stored, as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// protect recordings from deletion, however old they get
+    Pin {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// mark with a `<name>.keep` file, rather than an extended attribute
+        #[arg(long)]
+        sidecar: bool,
+    },
+
+    /// allow pinned recordings to be deleted again
+    Unpin {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+
+    /// put quarantined files back where they were
+    Restore {
+        #[arg(long)]
+        quarantine: PathBuf,
+
+        /// original paths of files, or directories to restore everything below
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+#[command(group(
+    ArgGroup::new("target")
+        .required(true)
+        .multiple(true)
+        .args(["target_use_percentage", "target_free_bytes", "target_free_inodes", "max_size"]),
+))]
+struct CleanupArgs {
+    /// repeat to clean several directories; those on the same filesystem are cleaned
+    /// together, oldest first across all of them
+    #[arg(short, long, required = true)]
+    directory: Vec<PathBuf>,
+
+    /// may contain dots, like `mp4.part`. A recording is named by its file with the
+    /// extension listed first
+    #[arg(short, long, default_values=[OsStr::new("mp4"), OsStr::new("jpg")])]
+    filter_extensions: Vec<OsString>,
+
+    #[arg(long)]
+    case_sensitive_extensions: bool,
+
+    /// only consider files matching one of these globs (or `re:` regexes) on the path below
+    /// `--directory`, instead of `--filter-extensions`
+    #[arg(long)]
+    include: Vec<Pattern>,
+
+    /// never consider files matching any of these globs (or `re:` regexes)
+    #[arg(long)]
+    exclude: Vec<Pattern>,
+
+    #[arg(short, long, alias = "low-watermark")]
+    target_use_percentage: Option<u8>,
+
+    /// only start deleting for `--target-use-percentage` once usage reaches this, so each
+    /// cleanup frees a useful amount rather than a few files per run
+    #[arg(long, requires = "target_use_percentage")]
+    high_watermark: Option<u8>,
+
+    /// measure percentages like `df`, of the space available to normal users, rather than
+    /// of the whole filesystem
+    #[arg(long)]
+    df_compatible: bool,
+
+    /// keep at least this much space available (e.g. `50G`)
+    #[arg(long, value_parser = parse_size)]
+    target_free_bytes: Option<u64>,
+
+    /// keep at least this many inodes free
+    #[arg(long)]
+    target_free_inodes: Option<u64>,
+
+    /// keep the directories themselves below this size (e.g. `2T`), counting every file in
+    /// them like `du`, whatever else is using the filesystem
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// delete every matching file older than this (e.g. `30d`), even if usage is below target
+    #[arg(long, value_parser = humantime::parse_duration)]
+    max_age: Option<Duration>,
+
+    /// never delete files younger than this (e.g. `7d`) to reach the target; exits with
+    /// status 3 if this stops cleanup before the target is reached
+    #[arg(long, value_parser = humantime::parse_duration)]
+    min_retention: Option<Duration>,
+
+    /// how many directory levels below `--directory` name a camera
+    #[arg(long, default_value_t = 1)]
+    camera_depth: usize,
+
+    /// limit a camera to a size (e.g. `front=100G`), even if usage is below target
+    #[arg(long, value_parser = parse_camera_quota)]
+    camera_quota: Vec<(String, u64)>,
+
+    /// delete from the camera furthest over its share (its quota, or an equal split),
+    /// rather than the globally oldest file
+    #[arg(long)]
+    fair_share: bool,
+
+    /// a glob (or `re:` regex) on the path below `--directory`, e.g. `*/continuous/**`; repeat
+    /// to list tiers from least to most important. Each tier is used up, oldest first, before
+    /// the next is touched, and files in no tier go last
+    #[arg(long)]
+    tier: Vec<Pattern>,
+
+    /// thin out snapshot series: past AGE, keep one per INTERVAL (e.g. `24h=10m`, `7d=1h`);
+    /// runs before anything else is deleted for usage, even if usage is below target
+    #[arg(long, value_name = "AGE=INTERVAL", value_parser = parse_thin_rule)]
+    thin: Vec<ThinRule>,
+
+    /// which recordings form a series for `--thin`
+    #[arg(long, default_values=[OsStr::new("jpg"), OsStr::new("jpeg")])]
+    thin_extensions: Vec<OsString>,
+
+    /// read this many directories at once while looking for files, which is much faster on
+    /// spinning disks and network filesystems with large trees
+    #[arg(long, default_value_t = 1)]
+    walk_threads: usize,
+
+    /// keep an index of every file seen in this file, so later runs only read directories
+    /// whose mtime has changed. A file changed in place, rather than added, removed or
+    /// renamed, is only noticed within an hour of it being indexed
+    #[arg(long)]
+    index: Option<PathBuf>,
+
+    /// only hold as many of the oldest recordings in memory as it takes to reach the
+    /// targets, looking again if those run out, rather than every one, for trees too big
+    /// to hold in memory
+    #[arg(long, conflicts_with_all = ["fair_share", "camera_quota", "thin", "max_size", "index"])]
+    bounded_memory: bool,
+
+    #[command(flatten)]
+    busy: BusyArgs,
+
+    #[command(flatten)]
+    quarantine: QuarantineArgs,
+
+    /// append what's deleted to this file, as JSON lines, with why and the usage around it;
+    /// see the `journal` subcommand
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// how soon to sync removals to disk; syncing every directory for every file is safest,
+    /// but slow on SD cards
+    #[arg(long, value_enum, default_value_t = Durability::PerFile)]
+    durability: Durability,
+
+    /// remove directories below `--directory` that cleanup leaves empty
+    #[arg(long)]
+    prune_empty_dirs: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct BusyArgs {
+    /// don't delete files modified this recently, as they may still be being written
+    #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+    quiet_period: Duration,
+
+    /// delete files even if they're recently modified, open for writing, or `flock`ed
+    #[arg(long)]
+    no_busy_check: bool,
 }
 
-fn main() -> Result<()> {
+#[derive(clap::Args, Debug)]
+struct QuarantineArgs {
+    /// move files here, on the same filesystem, rather than deleting them, so they can be
+    /// restored; they only free space once purged, by a later run that needs the space,
+    /// or after `--quarantine-grace`
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+
+    #[arg(long, default_value = "7d", value_parser = humantime::parse_duration)]
+    quarantine_grace: Duration,
+}
+
+impl QuarantineArgs {
+    fn open(&self) -> Result<Option<Quarantine>> {
+        self.quarantine.as_deref().map(Quarantine::new).transpose()
+    }
+}
+
+impl BusyArgs {
+    fn check(&self) -> Result<Option<BusyCheck>> {
+        if self.no_busy_check {
+            return Ok(None);
+        }
+        let quiet_since = unix_now()?.saturating_sub(self.quiet_period.as_secs());
+        Ok(Some(BusyCheck::new(quiet_since)?))
+    }
+}
+
+fn main() -> Result<ExitCode> {
     pretty_env_logger::formatted_builder()
         .filter_level(LevelFilter::Info)
         .parse_default_env()
@@ -41,129 +343,600 @@ fn main() -> Result<()> {
 
     match args.command {
         Command::ViolentCleanup {
-            directory,
-            filter_extensions,
-            target_use_percentage,
+            cleanup,
             actually_rm,
         } => {
-            let directory = directory.canonicalize()?;
-            let use_percentage = read_use_percentage(&directory)?;
-            let bytes_to_free = compute_bytes_to_free(&directory, target_use_percentage)?;
-            info!(
-                "current: {use_percentage}%, target: {target_use_percentage}%, need to free: {:.1}MB",
-                mb(bytes_to_free),
+            let action = if actually_rm {
+                Action::Remove
+            } else {
+                Action::DryRun
+            };
+            let mut remover = Remover::new(
+                action,
+                cleanup.durability,
+                cleanup.busy.check()?,
+                cleanup.quarantine.open()?,
+                open_journal(cleanup.journal.as_deref(), cleanup.df_compatible)?,
             );
-            if use_percentage < target_use_percentage {
-                return Ok(());
+            let outcome = run_cleanup(&cleanup, &mut remover)?;
+            remover.failures().summarise();
+            Ok(outcome.exit_code())
+        }
+        Command::Daemon {
+            cleanup,
+            actually_rm,
+            interval,
+            jitter,
+        } => {
+            let action = if actually_rm {
+                Action::Remove
+            } else {
+                Action::DryRun
+            };
+            let mut remover = Remover::new(
+                action,
+                cleanup.durability,
+                None,
+                cleanup.quarantine.open()?,
+                open_journal(cleanup.journal.as_deref(), cleanup.df_compatible)?,
+            );
+            let outcome =
+                daemon::run(&cleanup, &mut remover, interval, jitter.unwrap_or_default())?;
+            Ok(outcome.exit_code())
+        }
+        Command::Plan { cleanup, output } => {
+            let mut remover = Remover::new(
+                Action::Plan,
+                cleanup.durability,
+                cleanup.busy.check()?,
+                cleanup.quarantine.open()?,
+                None,
+            );
+            let outcome = run_cleanup(&cleanup, &mut remover)?;
+            remover.failures().summarise();
+            let plan = Plan {
+                created: unix_now()?,
+                directories: cleanup.directory.clone(),
+                entries: remover.take_planned(),
+            };
+            plan.write(&output)?;
+            info!("wrote {} files to {output:?}", plan.entries.len());
+            Ok(outcome.exit_code())
+        }
+        Command::Apply {
+            plan,
+            exclude,
+            busy,
+            quarantine,
+            journal,
+            durability,
+        } => {
+            let plan = Plan::read(&plan)?;
+            let mut remover = Remover::new(
+                Action::Remove,
+                durability,
+                busy.check()?,
+                quarantine.open()?,
+                open_journal(journal.as_deref(), false)?,
+            );
+            let applied = plan.apply(&exclude, &mut remover)?;
+            remover.sync();
+            remover.failures().summarise();
+            let outcome = match remover.failures().count() {
+                0 => Outcome::Done,
+                _ if applied > 0 => Outcome::Partial,
+                _ => Outcome::Failed,
+            };
+            Ok(outcome.exit_code())
+        }
+        Command::Journal {
+            journal,
+            since,
+            until,
+            camera,
+            json,
+        } => {
+            let entries = Journal::read(&journal)?.into_iter().filter(|entry| {
+                since.is_none_or(|since| entry.timestamp >= since)
+                    && until.is_none_or(|until| entry.timestamp <= until)
+                    && (camera.is_empty() || camera.contains(&entry.camera))
+            });
+            match print_journal(entries, json) {
+                // like piping into `head`
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+                result => result?,
             }
-            let mut matches = find_matching_files(&directory, filter_extensions)?;
-            let mut freed_bytes_estimate: u64 = 0;
-            while let Some((candidate, file_size)) = matches.pop_front() {
-                let use_percentage = read_use_percentage(&directory)?;
-                if use_percentage < target_use_percentage || freed_bytes_estimate >= bytes_to_free {
-                    break;
-                }
-                info!(
-                    "should remove: {:?} ({:.1} MB)",
-                    candidate,
-                    (file_size as f64) / 1024. / 1024.
-                );
-                if actually_rm {
-                    fs::remove_file(&candidate)?;
-                }
-                freed_bytes_estimate += file_size;
-
-                sync_all_the_way_down(&candidate)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Pin { paths, sidecar } => {
+            for path in paths {
+                pin::pin(&path, sidecar)?;
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Unpin { paths } => {
+            for path in paths {
+                pin::unpin(&path)?;
             }
-            info!("freed around {:.1}MB", mb(freed_bytes_estimate));
-            Ok(())
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Restore { quarantine, paths } => {
+            let mut failures = Failures::default();
+            let restored = Quarantine::new(&quarantine)?.restore(&paths, &mut failures)?;
+            info!("restored {restored} files");
+            failures.summarise();
+            let outcome = match failures.count() {
+                0 => Outcome::Done,
+                _ => Outcome::Partial,
+            };
+            Ok(outcome.exit_code())
         }
     }
 }
 
-fn mb(v: u64) -> f64 {
-    (v as f64) / 1024. / 1024.
+/// How a cleanup went, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    Done,
+    /// stopped at `--min-retention` before reaching the target
+    RetentionFloor,
+    /// errors with some files stopped it reaching the target
+    Partial,
+    /// errors stopped anything being deleted
+    Failed,
+}
+
+impl Outcome {
+    fn exit_code(self) -> ExitCode {
+        match self {
+            Outcome::Done => ExitCode::SUCCESS,
+            Outcome::RetentionFloor => ExitCode::from(3),
+            Outcome::Partial => ExitCode::from(4),
+            Outcome::Failed => ExitCode::from(5),
+        }
+    }
+}
+
+impl CleanupArgs {
+    fn validate(&self) -> Result<()> {
+        if let (Some(high_watermark), Some(target_use_percentage)) =
+            (self.high_watermark, self.target_use_percentage)
+            && high_watermark < target_use_percentage
+        {
+            bail!("--high-watermark must not be below --target-use-percentage");
+        }
+        if let (Some(max_age), Some(min_retention)) = (self.max_age, self.min_retention)
+            && min_retention > max_age
+        {
+            bail!("--min-retention must not be longer than --max-age");
+        }
+        Ok(())
+    }
+
+    fn targets(&self) -> Targets {
+        Targets {
+            use_percentage: self.target_use_percentage,
+            high_watermark: self.high_watermark,
+            free_bytes: self.target_free_bytes,
+            free_inodes: self.target_free_inodes,
+            max_size: self.max_size,
+        }
+    }
+
+    /// Whether anything is to be removed whatever the usage, as it ages past a limit.
+    fn has_policies(&self) -> bool {
+        self.max_age.is_some() || !self.camera_quota.is_empty() || !self.thin.is_empty()
+    }
+
+    fn selection(&self) -> Selection {
+        Selection {
+            filter_extensions: self.filter_extensions.clone(),
+            case_sensitive_extensions: self.case_sensitive_extensions,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            camera_depth: self.camera_depth,
+            tiers: self.tier.clone(),
+            skip_dirs: Vec::new(),
+            walk_threads: self.walk_threads,
+            index: self.index.clone(),
+            budget: None,
+            expire_before: None,
+            retain_from: None,
+            passed_over: HashSet::new(),
+        }
+    }
 }
 
-fn sync_all_the_way_down(starting_file: impl AsRef<Path>) -> Result<()> {
-    let mut current = starting_file.as_ref();
-    while let Some(parent) = current.parent() {
-        fs::File::open(parent)?.sync_all()?;
-        current = parent;
+fn run_cleanup(args: &CleanupArgs, remover: &mut Remover) -> Result<Outcome> {
+    args.validate()?;
+    let filesystems = group_by_filesystem(&args.directory)?;
+    check_quarantine(&filesystems, remover)?;
+    let mut outcome = Outcome::Done;
+    for directories in filesystems.values() {
+        let find = |selection: &Selection, failures: &mut Failures| {
+            find_matching_files(directories, selection, failures)
+        };
+        outcome = outcome.max(clean_filesystem(args, directories, remover, find)?);
+        remover.sync();
+    }
+    Ok(outcome)
+}
+
+/// The quarantine has to be on the only filesystem being cleaned, so files can be moved
+/// into it.
+fn check_quarantine(filesystems: &BTreeMap<u64, Vec<PathBuf>>, remover: &Remover) -> Result<()> {
+    if let Some(quarantine) = remover.quarantine() {
+        let dev = quarantine.dev()?;
+        if let Some(directories) = filesystems.get(&dev).filter(|_| filesystems.len() == 1) {
+            debug!(
+                "quarantining from {directories:?} to {:?}",
+                quarantine.root()
+            );
+        } else {
+            bail!(
+                "--quarantine {:?} must be on the same filesystem as every --directory",
+                quarantine.root()
+            );
+        }
     }
     Ok(())
 }
 
-fn find_matching_files(
-    directory: impl AsRef<Path>,
-    filter_extensions: Vec<OsString>,
-) -> Result<VecDeque<(PathBuf, u64)>> {
-    let mut matches = Vec::with_capacity(1024);
-    for entry in walkdir::WalkDir::new(directory).into_iter() {
-        match dir_entry_to_modified(entry) {
-            Ok(Some((path, modified, size))) => {
-                let ext = match path.extension() {
-                    Some(ext) => ext,
-                    None => continue,
-                };
-                if filter_extensions.iter().any(|e| e == ext) {
-                    matches.push((path, modified, size));
-                }
+/// Clean up `directories`, which are all on the same filesystem, as one, with `find`
+/// looking for what's in them.
+fn clean_filesystem(
+    args: &CleanupArgs,
+    directories: &[PathBuf],
+    remover: &mut Remover,
+    find: impl Fn(&Selection, &mut Failures) -> Result<Cameras>,
+) -> Result<Outcome> {
+    let filesystem = &directories[0];
+    let targets = args.targets();
+    let quarantine = remover.quarantine().cloned();
+    if let Some(quarantine) = &quarantine {
+        let usage = read_usage(filesystem, args.df_compatible)?;
+        purge_quarantine(
+            args,
+            directories,
+            quarantine,
+            remover,
+            usage.needs_cleanup(&targets),
+        )?;
+    }
+    let mut usage = read_usage(filesystem, args.df_compatible)?;
+    let mut to_free = compute_to_free(filesystem, &targets, args.df_compatible)?;
+    let has_policies = args.has_policies();
+    if !usage.needs_cleanup(&targets) && !has_policies && args.max_size.is_none() {
+        info!("{directories:?}: current: {usage}, target: {targets}");
+        return Ok(Outcome::Done);
+    }
+
+    let mut selection = args.selection();
+    if let Some(quarantine) = remover.quarantine() {
+        selection.skip_dirs.push(quarantine.root().to_path_buf());
+    }
+    // recordings modified since then are too young to delete
+    let retain_from = || -> Result<Option<u64>> {
+        args.min_retention
+            .map(|min_retention| unix_now().map(|now| now.saturating_sub(min_retention.as_secs())))
+            .transpose()
+    };
+    if args.bounded_memory {
+        selection.budget = Some(to_free);
+        selection.expire_before = args
+            .max_age
+            .map(|max_age| unix_now().map(|now| now.saturating_sub(max_age.as_secs())))
+            .transpose()?;
+        selection.retain_from = retain_from()?;
+    }
+    let failures_before = remover.failures().count();
+    let mut cameras = find(&selection, remover.failures_mut())?;
+    let tree_bytes = args.max_size.map(|max_size| {
+        to_free.bytes = to_free
+            .bytes
+            .max(cameras.total_bytes.saturating_sub(max_size));
+        cameras.total_bytes
+    });
+    usage.tree_bytes = tree_bytes;
+    info!("{directories:?}: current: {usage}, target: {targets}, need to free: {to_free}");
+    let needs_cleanup = usage.needs_cleanup(&targets);
+    if !needs_cleanup && !has_policies {
+        return Ok(Outcome::Done);
+    }
+    // the filesystem can be re-read as we go, but the directories' size can only be estimated.
+    // Until removals are synced, some filesystems don't show the space as free yet, so the
+    // loop below also stops once the estimate covers what's needed
+    let measure = |progress: &Progress| -> Result<Usage> {
+        let mut usage = read_usage(filesystem, args.df_compatible)?;
+        // quarantined files are out of the walk too
+        let gone = progress.dealt_with().bytes;
+        usage.tree_bytes = tree_bytes.map(|tree_bytes| tree_bytes.saturating_sub(gone));
+        Ok(usage)
+    };
+
+    for (camera, quota) in &args.camera_quota {
+        if !cameras.set_quota(camera, *quota) {
+            warn!("no matching files for camera {camera:?}, ignoring its quota");
+        }
+    }
+    let mut progress = Progress::new(quarantine.is_some());
+
+    if let Some(max_age) = args.max_age {
+        let cutoff = unix_now()?.saturating_sub(max_age.as_secs());
+        let reason = format!("older than max age {}", humantime::format_duration(max_age));
+        for candidate in cameras.take_older_than(cutoff) {
+            if remover.remove(&candidate, &reason)? {
+                progress.add(&candidate);
             }
-            Ok(None) => continue,
-            Err(e) => {
-                log::debug!("error reading directory, ignoring: {e:?}");
+        }
+    }
+
+    let hold_back = |cameras: &mut Cameras| -> Result<usize> {
+        Ok(match retain_from()? {
+            Some(retain_from) => cameras.hold_back_from(retain_from),
+            None => 0,
+        })
+    };
+    let mut held_back = hold_back(&mut cameras)?;
+
+    if !args.thin.is_empty() {
+        let in_series =
+            |candidate: &Candidate| candidate.only_has_extensions(&args.thin_extensions);
+        for candidate in cameras.take_thinned(unix_now()?, &args.thin, in_series) {
+            if remover.remove(&candidate, "thinned out of its series")? {
+                progress.add(&candidate);
+            }
+        }
+    }
+
+    for (camera, quota) in &args.camera_quota {
+        let reason = format!("camera {camera} over quota {:.1}MB", mb(*quota));
+        while cameras.over_quota(camera) {
+            let Some(candidate) = cameras.pop_front(camera) else {
+                warn!(
+                    "camera {camera} is over quota, but its files are younger than minimum retention"
+                );
+                break;
+            };
+            if remover.remove(&candidate, &reason)? {
+                progress.add(&candidate);
+            }
+        }
+    }
+
+    let mut outcome = Outcome::Done;
+    let mut usage = measure(&progress)?;
+    // looking again only finds anything new once the last walk's files have changed
+    let mut changed_since_walk = held_back > 0;
+    while needs_cleanup && !usage.meets(&targets) && !progress.dealt_with().covers(&to_free) {
+        let Some(camera) = cameras.next_camera(args.fair_share) else {
+            if cameras.incomplete && changed_since_walk {
+                debug!("used up the oldest files found, looking for more");
+                selection.budget = Some(to_free.saturating_sub(progress.dealt_with()));
+                selection.retain_from = retain_from()?;
+                cameras = find(&selection, remover.failures_mut())?;
+                held_back = hold_back(&mut cameras)?;
+                changed_since_walk = held_back > 0;
                 continue;
             }
+            let held_back = cameras.held_back + held_back;
+            if held_back > 0 {
+                error!(
+                    "stopping: {held_back} remaining files are younger than minimum retention {}, still at {usage} (target: {targets})",
+                    humantime::format_duration(args.min_retention.expect("files are held back")),
+                );
+                outcome = Outcome::RetentionFloor;
+            }
+            break;
+        };
+        let candidate = cameras.pop_front(&camera).expect("next camera has files");
+        if candidate.space().is_empty() {
+            debug!("skipping {:?}: removing it frees nothing", candidate.path());
+            continue;
+        }
+        let removed = remover.remove(&candidate, "over target usage")?;
+        if removed {
+            progress.add(&candidate);
+            changed_since_walk = true;
+        }
+        if args.bounded_memory && (!removed || !remover.deletes()) {
+            // so looking again doesn't find it again
+            changed_since_walk |= selection.passed_over.insert(candidate.path().to_path_buf());
+        }
+        usage = measure(&progress)?;
+    }
+    if progress.quarantining {
+        info!("quarantined around {}", progress.quarantined);
+    }
+    if let Some(quarantine) = &quarantine
+        && needs_cleanup
+        && !usage.meets(&targets)
+    {
+        // what was just moved there set aside enough, but it's only freed once purged
+        progress.freed += purge_quarantine(args, directories, quarantine, remover, true)?;
+        usage = measure(&progress)?;
+    }
+    info!("freed around {}", progress.freed);
+    if remover.failures().count() > failures_before && !usage.meets(&targets) {
+        outcome = outcome.max(if progress.dealt_with().is_empty() {
+            Outcome::Failed
+        } else {
+            Outcome::Partial
+        });
+    }
+    if args.prune_empty_dirs {
+        remover.prune_empty_dirs(directories)?;
+    }
+    Ok(outcome)
+}
+
+/// Roughly what a cleanup has got rid of so far: freed, or moved into the quarantine,
+/// which frees nothing until it's purged.
+struct Progress {
+    quarantining: bool,
+    freed: Space,
+    quarantined: Space,
+}
+
+impl Progress {
+    fn new(quarantining: bool) -> Progress {
+        Progress {
+            quarantining,
+            freed: Space::default(),
+            quarantined: Space::default(),
+        }
+    }
+
+    fn add(&mut self, candidate: &Candidate) {
+        if self.quarantining {
+            self.quarantined += candidate.space();
+        } else {
+            self.freed += candidate.space();
         }
     }
-    matches.sort_unstable_by_key(|(_, modified, _)| *modified);
-    Ok(matches
-        .into_iter()
-        .map(|(path, _, size)| (path, size))
-        .collect())
+
+    fn dealt_with(&self) -> Space {
+        let mut space = self.freed;
+        space += self.quarantined;
+        space
+    }
+}
+
+/// Purge files quarantined for longer than the grace period, and then, if space is
+/// needed and the targets aren't met, as many as it takes, longest-quarantined first,
+/// returning roughly what was freed.
+fn purge_quarantine(
+    args: &CleanupArgs,
+    directories: &[PathBuf],
+    quarantine: &Quarantine,
+    remover: &mut Remover,
+    needs_space: bool,
+) -> Result<Space> {
+    let filesystem = &directories[0];
+    let targets = args.targets();
+    let grace_ends = unix_now()?.saturating_sub(args.quarantine.quarantine_grace.as_secs());
+    let mut usage = read_usage(filesystem, args.df_compatible)?;
+    let to_free = compute_to_free(filesystem, &targets, args.df_compatible)?;
+    let mut freed_estimate = Space::default();
+    for (file, quarantined_at) in quarantine.list(remover.failures_mut()) {
+        let reason = if quarantined_at < grace_ends {
+            "quarantined for longer than the grace period"
+        } else if needs_space && !usage.meets(&targets) && !freed_estimate.covers(&to_free) {
+            "space is needed"
+        } else {
+            break;
+        };
+        // the camera that recorded it, from where it was before being quarantined
+        let camera = match quarantine.original_of(&file.path) {
+            Some(original) => camera_of(relative_to(directories, &original), args.camera_depth),
+            None => ".".to_string(),
+        };
+        if remover.purge(&file, &camera, reason)? {
+            freed_estimate += file.frees();
+        }
+        usage = read_usage(filesystem, args.df_compatible)?;
+    }
+    Ok(freed_estimate)
 }
 
-fn read_use_percentage(directory: impl AsRef<Path>) -> Result<u8> {
-    let stat = nix::sys::statvfs::statvfs(directory.as_ref())?;
-    let total_blocks = stat.blocks();
-    let free_blocks = stat.blocks_free();
+fn print_journal(entries: impl Iterator<Item = JournalEntry>, json: bool) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for entry in entries {
+        if json {
+            writeln!(stdout, "{}", serde_json::to_string(&entry)?)?;
+        } else {
+            writeln!(stdout, "{entry}")?;
+        }
+    }
+    stdout.flush()
+}
 
-    let use_percentage = u8::try_from(100u64 - (free_blocks * 100 / total_blocks))?;
-    Ok(use_percentage)
+fn open_journal(path: Option<&Path>, df_compatible: bool) -> Result<Option<Journal>> {
+    path.map(|path| Journal::open(path, df_compatible))
+        .transpose()
 }
 
-fn compute_bytes_to_free(directory: impl AsRef<Path>, target_use_percentage: u8) -> Result<u64> {
-    let stat = nix::sys::statvfs::statvfs(directory.as_ref())?;
-    let total_blocks = stat.blocks();
-    let free_blocks = stat.blocks_free();
-    let used_blocks = total_blocks - free_blocks;
-    let target_used_blocks = total_blocks * u64::from(target_use_percentage) / 100;
+fn unix_now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
 
-    if used_blocks <= target_used_blocks {
-        return Ok(0);
+fn mb(v: u64) -> f64 {
+    (v as f64) / 1024. / 1024.
+}
+
+/// A size like `512`, `300M` or `2T`, in binary units.
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let shift = match unit.trim_end_matches(['B', 'b']).trim_end_matches('i') {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        "P" | "p" => 50,
+        _ => bail!("unrecognised size unit {unit:?} in {s:?}"),
+    };
+    let value: u64 = digits
+        .parse()
+        .with_context(|| anyhow!("parsing size {s:?}"))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("size {s:?} is too large"))
+}
+
+/// A UTC timestamp like `2024-05-01 12:00:00`, or a duration ago like `30d`, in unix time.
+fn parse_time(s: &str) -> Result<u64> {
+    if let Ok(time) = humantime::parse_rfc3339_weak(s) {
+        return Ok(time.duration_since(UNIX_EPOCH)?.as_secs());
     }
+    let ago = humantime::parse_duration(s)
+        .with_context(|| anyhow!("expected a timestamp or a duration, not {s:?}"))?;
+    Ok(unix_now()?.saturating_sub(ago.as_secs()))
+}
 
-    let block_size = stat.fragment_size();
-    Ok((used_blocks - target_used_blocks) * block_size)
+fn parse_thin_rule(s: &str) -> Result<ThinRule> {
+    let (after, keep_one_per) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected AGE=INTERVAL, not {s:?}"))?;
+    Ok(ThinRule {
+        after: humantime::parse_duration(after)?,
+        keep_one_per: humantime::parse_duration(keep_one_per)?,
+    })
 }
 
-fn dir_entry_to_modified(
-    entry: walkdir::Result<walkdir::DirEntry>,
-) -> Result<Option<(PathBuf, u64, u64)>> {
-    let entry = entry?;
-    if !entry.file_type().is_file() {
-        return Ok(None);
+fn parse_camera_quota(s: &str) -> Result<(String, u64)> {
+    let (camera, size) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected CAMERA=SIZE, not {s:?}"))?;
+    Ok((camera.to_string(), parse_size(size)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size(" 10K ").unwrap(), 10 << 10);
+        assert_eq!(parse_size("2MiB").unwrap(), 2 << 20);
+        assert_eq!(parse_size("3gb").unwrap(), 3 << 30);
+        assert_eq!(parse_size("1TB").unwrap(), 1 << 40);
+        assert_eq!(parse_size("1P").unwrap(), 1 << 50);
+        for bad in ["", "M", "1.5G", "12X", "20000P", "99999999999999999999"] {
+            assert!(parse_size(bad).is_err(), "{bad:?}");
+        }
     }
 
-    let path = entry.path();
-    let metadata = entry
-        .metadata()
-        .with_context(|| anyhow!("reading {:?}", &path))?;
-    let modified = metadata
-        .modified()?
-        .duration_since(std::time::UNIX_EPOCH)?
-        .as_secs();
-    let size = metadata.len();
-    Ok(Some((path.to_path_buf(), modified, size)))
+    #[test]
+    fn thin_rules() {
+        let rule = parse_thin_rule("7d=1h").unwrap();
+        assert_eq!(rule.after, Duration::from_secs(7 * 24 * 60 * 60));
+        assert_eq!(rule.keep_one_per, Duration::from_secs(60 * 60));
+        for bad in ["7d", "7d=", "=1h", "week=1h"] {
+            assert!(parse_thin_rule(bad).is_err(), "{bad:?}");
+        }
+    }
 }
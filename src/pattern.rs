@@ -0,0 +1,37 @@
+use anyhow::{Error, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::bytes::Regex;
+use std::path::Path;
+use std::str::FromStr;
+
+/// A glob, like `*/continuous/**`, or a regex prefixed with `re:`, like `re:\.mp4(\.part)?$`,
+/// matched against paths relative to `--directory`.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn is_match(&self, relative: &Path) -> bool {
+        match self {
+            Pattern::Glob(glob) => glob.is_match(relative),
+            Pattern::Regex(regex) => regex.is_match(relative.as_os_str().as_encoded_bytes()),
+        }
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(regex) = s.strip_prefix("re:") {
+            return Ok(Pattern::Regex(Regex::new(regex)?));
+        }
+        let glob = GlobBuilder::new(s)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        Ok(Pattern::Glob(glob))
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::{Context, Result, anyhow, bail};
+use log::info;
+use nix::errno::Errno;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Files with this extended attribute are never deleted.
+const XATTR: &str = "user.cam-tool.keep";
+
+/// Nor are files with a `<name>.keep` file next to them.
+pub fn sidecar_of(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".keep");
+    PathBuf::from(name)
+}
+
+pub fn is_pinned(path: &Path, sidecars: &HashSet<PathBuf>) -> bool {
+    sidecars.contains(&sidecar_of(path)) || matches!(xattr::get(path, XATTR), Ok(Some(_)))
+}
+
+/// Mark a file as protected, with the xattr if the filesystem supports it,
+/// or with a sidecar if asked to, or if it doesn't.
+pub fn pin(path: &Path, sidecar: bool) -> Result<()> {
+    if !path.is_file() {
+        bail!("{path:?} is not a file");
+    }
+    if !sidecar {
+        match xattr::set(path, XATTR, b"") {
+            Ok(()) => {
+                info!("pinned {path:?}");
+                return Ok(());
+            }
+            Err(e) if e.raw_os_error() == Some(Errno::EOPNOTSUPP as i32) => {
+                info!("{path:?} doesn't support xattrs, using a sidecar");
+            }
+            Err(e) => return Err(e).with_context(|| anyhow!("setting {XATTR} on {path:?}")),
+        }
+    }
+    let sidecar = sidecar_of(path);
+    fs::File::create(&sidecar).with_context(|| anyhow!("creating {sidecar:?}"))?;
+    info!("pinned {path:?} with {sidecar:?}");
+    Ok(())
+}
+
+/// Remove both kinds of mark, if present.
+pub fn unpin(path: &Path) -> Result<()> {
+    match xattr::remove(path, XATTR) {
+        Ok(()) => {}
+        Err(e)
+            if e.raw_os_error() == Some(Errno::ENODATA as i32)
+                || e.raw_os_error() == Some(Errno::EOPNOTSUPP as i32) => {}
+        Err(e) => return Err(e).with_context(|| anyhow!("removing {XATTR} from {path:?}")),
+    }
+    let sidecar = sidecar_of(path);
+    match fs::remove_file(&sidecar) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| anyhow!("removing {sidecar:?}")),
+    }
+    info!("unpinned {path:?}");
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use anyhow::{Context, Result, anyhow};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::candidates::{Candidate, FileInfo, file_info, relative_to};
+use crate::pattern::Pattern;
+use crate::pin;
+use crate::remove::Remover;
+
+/// Everything a cleanup would delete, in order, to be reviewed before it's applied.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    /// unix time of planning
+    pub created: u64,
+    /// the `--directory` it was planned for, which `--exclude` is relative to
+    #[serde(default)]
+    pub directories: Vec<PathBuf>,
+    pub entries: Vec<PlanEntry>,
+}
+
+/// A file to delete, and the state it was in when planned.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanEntry {
+    /// entries with the same recording are deleted together, or not at all
+    pub recording: usize,
+    #[serde(default)]
+    pub camera: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: u64,
+    pub inode: u64,
+    pub reason: String,
+}
+
+impl PlanEntry {
+    pub fn new(recording: usize, camera: &str, file: &FileInfo, reason: &str) -> PlanEntry {
+        PlanEntry {
+            recording,
+            camera: camera.to_string(),
+            path: file.path.clone(),
+            size: file.size,
+            mtime: file.modified,
+            inode: file.inode,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn unchanged(&self, file: &FileInfo) -> bool {
+        self.inode == file.inode && self.size == file.size && self.mtime == file.modified
+    }
+}
+
+impl Plan {
+    pub fn read(path: &Path) -> Result<Plan> {
+        let file = fs::File::open(path).with_context(|| anyhow!("opening {path:?}"))?;
+        serde_json::from_reader(BufReader::new(file)).with_context(|| anyhow!("parsing {path:?}"))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let file = fs::File::create(path).with_context(|| anyhow!("creating {path:?}"))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Delete every recording in the plan, unless any of its files have changed, been
+    /// pinned, or match `exclude` since, returning how many were. Files planned to be
+    /// purged from the remover's quarantine are purged, rather than quarantined again.
+    pub fn apply(&self, exclude: &[Pattern], remover: &mut Remover) -> Result<usize> {
+        let mut applied = 0;
+        'recordings: for entries in self.entries.chunk_by(|a, b| a.recording == b.recording) {
+            let mut files = Vec::with_capacity(entries.len());
+            let pin_sidecars: HashSet<PathBuf> = entries
+                .iter()
+                .map(|entry| pin::sidecar_of(&entry.path))
+                .filter(|sidecar| sidecar.exists())
+                .collect();
+            for entry in entries {
+                let relative = relative_to(&self.directories, &entry.path);
+                if exclude.iter().any(|p| p.is_match(relative)) {
+                    info!("skipping {:?}: excluded", entry.path);
+                    continue 'recordings;
+                }
+                if pin::is_pinned(&entry.path, &pin_sidecars) {
+                    info!("skipping {:?}: pinned since planning", entry.path);
+                    continue 'recordings;
+                }
+                match file_info(&entry.path) {
+                    Ok(file) if entry.unchanged(&file) => files.push(file),
+                    Ok(_) => {
+                        warn!("skipping {:?}: changed since planning", entry.path);
+                        continue 'recordings;
+                    }
+                    Err(e) => {
+                        remover.failures_mut().record(&e);
+                        continue 'recordings;
+                    }
+                }
+            }
+            // purges are planned a file at a time
+            if let [file] = &files[..]
+                && remover
+                    .quarantine()
+                    .is_some_and(|q| q.original_of(&file.path).is_some())
+            {
+                if remover.purge(file, &entries[0].camera, &entries[0].reason)? {
+                    applied += 1;
+                }
+                continue;
+            }
+            // only the thinning cares which files matched
+            let matching = files.len();
+            let candidate = Candidate::new(files, matching, 0, entries[0].camera.clone());
+            if remover.remove(&candidate, &entries[0].reason)? {
+                applied += 1;
+            }
+        }
+        info!("applied {applied} recordings from the plan");
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remove::{Action, Durability};
+
+    #[test]
+    fn only_unchanged_recordings_are_applied() {
+        let dir = std::env::temp_dir().join(format!("cam-tool-plan-{}", std::process::id()));
+        fs::create_dir_all(dir.join("skip")).unwrap();
+        let files = [
+            (0, "old.mp4"),
+            (1, "changed.mp4"),
+            (2, "pinned.mp4"),
+            (2, "pinned.jpg"),
+            (3, "skip/old.mp4"),
+        ];
+        let mut plan = Plan {
+            directories: vec![dir.clone()],
+            ..Plan::default()
+        };
+        for (recording, name) in files {
+            let path = dir.join(name);
+            fs::write(&path, b"recording").unwrap();
+            let file = file_info(&path).unwrap();
+            plan.entries
+                .push(PlanEntry::new(recording, "front", &file, "test"));
+        }
+        // since planning, one recording grew, and one had a file pinned
+        fs::write(dir.join("changed.mp4"), b"a longer recording").unwrap();
+        fs::write(pin::sidecar_of(&dir.join("pinned.jpg")), b"").unwrap();
+        let exclude = ["skip/**".parse().unwrap()];
+
+        let mut remover = Remover::new(Action::Remove, Durability::None, None, None, None);
+        let applied = plan.apply(&exclude, &mut remover).unwrap();
+        let left = files.map(|(_, name)| dir.join(name).exists());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(applied, 1);
+        assert_eq!(left, [false, true, true, true, true]);
+        assert_eq!(remover.failures().count(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{Context, Result, anyhow, bail};
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+use crate::candidates::{FileInfo, file_info};
+use crate::errors::Failures;
+
+/// Where files are moved to instead of being deleted, under their original absolute path,
+/// so they can be restored until they're purged.
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    root: PathBuf,
+}
+
+impl Quarantine {
+    pub fn new(root: &Path) -> Result<Quarantine> {
+        fs::create_dir_all(root).with_context(|| anyhow!("creating {root:?}"))?;
+        let root = root
+            .canonicalize()
+            .with_context(|| anyhow!("resolving {root:?}"))?;
+        Ok(Quarantine { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn dev(&self) -> Result<u64> {
+        Ok(self.root.metadata()?.dev())
+    }
+
+    fn path_for(&self, original: &Path) -> PathBuf {
+        let relative: PathBuf = original
+            .components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .collect();
+        self.root.join(relative)
+    }
+
+    pub fn original_of(&self, quarantined: &Path) -> Option<PathBuf> {
+        Some(Path::new("/").join(quarantined.strip_prefix(&self.root).ok()?))
+    }
+
+    /// Move `path` in, returning where it went.
+    pub fn put(&self, path: &Path) -> Result<PathBuf> {
+        let destination = self.path_for(path);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).with_context(|| anyhow!("creating {parent:?}"))?;
+        }
+        fs::rename(path, &destination)
+            .with_context(|| anyhow!("quarantining {path:?} to {destination:?}"))?;
+        Ok(destination)
+    }
+
+    /// Everything in quarantine, with when it went in (its ctime, as it was renamed then),
+    /// longest-quarantined first. Errors with its files are recorded in `failures`.
+    pub fn list(&self, failures: &mut Failures) -> Vec<(FileInfo, u64)> {
+        let mut files = Vec::new();
+        for entry in walkdir::WalkDir::new(&self.root) {
+            let listed = entry
+                .map_err(anyhow::Error::from)
+                .and_then(|entry| {
+                    if !entry.file_type().is_file() {
+                        return Ok(None);
+                    }
+                    let metadata = entry
+                        .metadata()
+                        .with_context(|| anyhow!("reading {:?}", entry.path()))?;
+                    let quarantined_at = u64::try_from(metadata.ctime()).unwrap_or_default();
+                    Ok(Some((file_info(entry.path())?, quarantined_at)))
+                })
+                .context("reading the quarantine");
+            match listed {
+                Ok(Some(listed)) => files.push(listed),
+                Ok(None) => {}
+                Err(e) => failures.record(&e),
+            }
+        }
+        files.sort_unstable_by_key(|(_, quarantined_at)| *quarantined_at);
+        files
+    }
+
+    /// Put back everything originally at or below any of `originals`.
+    pub fn restore(&self, originals: &[PathBuf], failures: &mut Failures) -> Result<usize> {
+        let originals = originals
+            .iter()
+            .map(std::path::absolute)
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut restored = 0;
+        for (file, _) in self.list(failures) {
+            let Some(original) = self.original_of(&file.path) else {
+                continue;
+            };
+            if !originals.iter().any(|prefix| original.starts_with(prefix)) {
+                continue;
+            }
+            if fs::symlink_metadata(&original).is_ok() {
+                warn!("not restoring {original:?}: something else is there now");
+                continue;
+            }
+            if let Some(parent) = original.parent() {
+                fs::create_dir_all(parent).with_context(|| anyhow!("creating {parent:?}"))?;
+            }
+            fs::rename(&file.path, &original)
+                .with_context(|| anyhow!("restoring {:?} to {original:?}", file.path))?;
+            info!("restored {original:?}");
+            restored += 1;
+            self.prune_above(&file.path);
+        }
+        if restored == 0 {
+            bail!("nothing in {:?} to restore for {originals:?}", self.root);
+        }
+        Ok(restored)
+    }
+
+    /// Tidy up directories left empty in the quarantine.
+    pub fn prune_above(&self, path: &Path) {
+        let mut current = path.parent();
+        while let Some(dir) = current {
+            if dir == self.root || fs::remove_dir(dir).is_err() {
+                break;
+            }
+            current = dir.parent();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restoring_puts_files_back_where_they_were() {
+        let dir = std::env::temp_dir().join(format!("cam-tool-quarantine-{}", std::process::id()));
+        fs::create_dir_all(dir.join("front")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let quarantine = Quarantine::new(&dir.join("quarantine")).unwrap();
+        let original = dir.join("front/clip.mp4");
+        fs::write(&original, b"recording").unwrap();
+
+        let quarantined = quarantine.put(&original).unwrap();
+        assert_eq!(quarantined, quarantine.path_for(&original));
+        assert!(quarantined.starts_with(quarantine.root()));
+        assert_eq!(quarantine.original_of(&quarantined), Some(original.clone()));
+        assert_eq!(quarantine.original_of(&original), None);
+        assert!(!original.exists());
+
+        let mut failures = Failures::default();
+        let listed: Vec<PathBuf> = quarantine
+            .list(&mut failures)
+            .into_iter()
+            .map(|(file, _)| file.path)
+            .collect();
+        assert_eq!(listed, std::slice::from_ref(&quarantined));
+        assert!(
+            quarantine
+                .restore(&[dir.join("back")], &mut failures)
+                .is_err()
+        );
+        let restored = quarantine.restore(&[dir.join("front")], &mut failures);
+        let (back, emptied) = (original.exists(), !quarantined.parent().unwrap().exists());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(restored.unwrap(), 1);
+        assert!(back && emptied);
+        assert_eq!(failures.count(), 0);
+    }
+}
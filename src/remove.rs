@@ -0,0 +1,397 @@
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info};
+use nix::unistd::syncfs;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::busy::BusyCheck;
+use crate::candidates::{Candidate, FileInfo};
+use crate::errors::Failures;
+use crate::journal::{Journal, JournalEntry};
+use crate::plan::PlanEntry;
+use crate::quarantine::Quarantine;
+use crate::service::{self, Stopped};
+use crate::usage::{Space, Usage};
+use crate::{mb, unix_now};
+
+pub enum Action {
+    /// only log what would be removed
+    DryRun,
+    Remove,
+    /// log it, and add it to a plan to apply later
+    Plan,
+}
+
+/// How hard to make sure removals survive a crash, against how long they take.
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Durability {
+    /// sync every directory above each file as it's removed
+    PerFile,
+    /// sync each directory files were removed from once, at the end
+    PerDirectoryBatch,
+    /// sync each filesystem once, at the end
+    Syncfs,
+    /// leave it to the kernel
+    None,
+}
+
+pub struct Remover {
+    action: Action,
+    durability: Durability,
+    busy: Option<BusyCheck>,
+    /// move files here, rather than deleting them
+    quarantine: Option<Quarantine>,
+    /// record what's deleted here
+    journal: Option<Journal>,
+    /// directories we've removed files from
+    touched: BTreeSet<PathBuf>,
+    /// directories changed, but not yet synced
+    unsynced: BTreeSet<PathBuf>,
+    planned: Vec<PlanEntry>,
+    recordings: usize,
+    /// since `take_freed` was last called; moving files into the quarantine frees nothing
+    freed: Space,
+    failures: Failures,
+}
+
+impl Remover {
+    pub fn new(
+        action: Action,
+        durability: Durability,
+        busy: Option<BusyCheck>,
+        quarantine: Option<Quarantine>,
+        journal: Option<Journal>,
+    ) -> Remover {
+        Remover {
+            action,
+            durability,
+            busy,
+            quarantine,
+            journal,
+            touched: BTreeSet::new(),
+            unsynced: BTreeSet::new(),
+            planned: Vec::new(),
+            recordings: 0,
+            freed: Space::default(),
+            failures: Failures::default(),
+        }
+    }
+
+    /// Whether removed files are actually gone, rather than only logged or planned.
+    pub fn deletes(&self) -> bool {
+        matches!(self.action, Action::Remove)
+    }
+
+    /// Replace the busy check, which only sees what was busy when it was made.
+    pub fn set_busy(&mut self, busy: Option<BusyCheck>) {
+        self.busy = busy;
+    }
+
+    pub fn quarantine(&self) -> Option<&Quarantine> {
+        self.quarantine.as_ref()
+    }
+
+    pub fn failures(&self) -> &Failures {
+        &self.failures
+    }
+
+    pub fn failures_mut(&mut self) -> &mut Failures {
+        &mut self.failures
+    }
+
+    /// The errors with files since the last time this was asked.
+    pub fn take_failures(&mut self) -> Failures {
+        std::mem::take(&mut self.failures)
+    }
+
+    pub fn take_planned(&mut self) -> Vec<PlanEntry> {
+        std::mem::take(&mut self.planned)
+    }
+
+    /// Roughly how much has been freed since the last time this was asked.
+    pub fn take_freed(&mut self) -> Space {
+        std::mem::take(&mut self.freed)
+    }
+
+    /// Remove the recording, unless it looks like it's still being written, returning whether
+    /// all of it is gone. Errors with its files are recorded in `failures`, not returned.
+    /// Fails with `Stopped` once asked to stop, rather than starting on another.
+    pub fn remove(&mut self, candidate: &Candidate, reason: &str) -> Result<bool> {
+        if service::stopping() {
+            return Err(Stopped.into());
+        }
+        service::keep_alive();
+        if let Some(busy) = &self.busy {
+            for file in &candidate.files {
+                if let Some(why) = busy.why_busy(file) {
+                    info!("skipping {:?}: {why}", file.path);
+                    return Ok(false);
+                }
+            }
+        }
+        let sidecars = match candidate.files.len() {
+            1 => String::new(),
+            2 => " and 1 file sharing its stem".to_string(),
+            n => format!(" and {} files sharing its stem", n - 1),
+        };
+        info!(
+            "should remove: {:?}{sidecars} ({:.1} MB): {reason}",
+            candidate.path(),
+            mb(candidate.size)
+        );
+        self.recordings += 1;
+        let mut journal_entries = Vec::new();
+        let mut removed_all = true;
+        match self.action {
+            Action::DryRun => {
+                if self.quarantine.is_none() {
+                    self.freed += candidate.space();
+                }
+            }
+            Action::Remove => {
+                let usage_before = self.journal_usage(candidate.path())?;
+                let mut removed = Vec::with_capacity(candidate.files.len());
+                for file in &candidate.files {
+                    let result = match &self.quarantine {
+                        Some(quarantine) => quarantine.put(&file.path).map(Some),
+                        None => fs::remove_file(&file.path)
+                            .map(|()| None)
+                            .with_context(|| anyhow!("removing {:?}", file.path)),
+                    };
+                    match result {
+                        Ok(quarantined) => {
+                            match &quarantined {
+                                Some(destination) => self.changed(destination),
+                                None => self.freed += file.frees(),
+                            }
+                            removed.push((file, quarantined));
+                        }
+                        Err(e) => {
+                            self.failures.record(&e);
+                            removed_all = false;
+                        }
+                    }
+                }
+                if let Some(parent) = candidate.path().parent() {
+                    self.touched.insert(parent.to_path_buf());
+                }
+                if let Some(usage_before) = usage_before {
+                    let usage_after = self.journal_usage(candidate.path())?.expect("journalling");
+                    let timestamp = unix_now()?;
+                    for (file, quarantined) in removed {
+                        let mut entry = JournalEntry::new(
+                            timestamp,
+                            file,
+                            &candidate.camera,
+                            reason,
+                            usage_before.clone(),
+                            usage_after.clone(),
+                        );
+                        entry.quarantined = quarantined;
+                        journal_entries.push(entry);
+                    }
+                }
+            }
+            Action::Plan => {
+                for file in &candidate.files {
+                    self.planned.push(PlanEntry::new(
+                        self.recordings,
+                        &candidate.camera,
+                        file,
+                        reason,
+                    ));
+                }
+                return Ok(true);
+            }
+        }
+        self.changed(candidate.path());
+        if let Some(journal) = &mut self.journal
+            && !journal_entries.is_empty()
+        {
+            journal.record(&journal_entries)?;
+        }
+        Ok(removed_all)
+    }
+
+    /// Delete a file from the quarantine for good, returning whether it's gone, or planned
+    /// to be.
+    pub fn purge(&mut self, file: &FileInfo, camera: &str, reason: &str) -> Result<bool> {
+        if service::stopping() {
+            return Err(Stopped.into());
+        }
+        service::keep_alive();
+        // the quarantine is listed again after cleaning up, with nothing purged yet
+        if let Action::Plan = self.action
+            && self.planned.iter().any(|entry| entry.path == file.path)
+        {
+            return Ok(false);
+        }
+        info!(
+            "should purge: {:?} ({:.1} MB): {reason}",
+            file.path,
+            mb(file.allocated)
+        );
+        if let Action::Plan = self.action {
+            self.recordings += 1;
+            self.planned
+                .push(PlanEntry::new(self.recordings, camera, file, reason));
+            return Ok(true);
+        }
+        if let Action::Remove = self.action {
+            let usage_before = self.journal_usage(&file.path)?;
+            if let Err(e) = fs::remove_file(&file.path) {
+                self.failures
+                    .record(&anyhow!(e).context(format!("purging {:?}", file.path)));
+                return Ok(false);
+            }
+            self.changed(&file.path);
+            if let Some(usage_before) = usage_before {
+                let usage_after = self.journal_usage(&file.path)?.expect("journalling");
+                let original = self
+                    .quarantine
+                    .as_ref()
+                    .and_then(|quarantine| quarantine.original_of(&file.path));
+                let mut entry = JournalEntry::new(
+                    unix_now()?,
+                    file,
+                    camera,
+                    &format!("purged from quarantine: {reason}"),
+                    usage_before,
+                    usage_after,
+                );
+                if let Some(original) = original {
+                    entry.path = original;
+                    entry.quarantined = Some(file.path.clone());
+                }
+                self.journal
+                    .as_mut()
+                    .expect("journalling")
+                    .record(&[entry])?;
+            }
+            if let Some(quarantine) = &self.quarantine {
+                quarantine.prune_above(&file.path);
+            }
+        }
+        self.freed += file.frees();
+        Ok(true)
+    }
+
+    /// Make the removal of `path`, or its arrival, durable now, or note its directory to
+    /// `sync` later, depending on `durability`.
+    fn changed(&mut self, path: &Path) {
+        match self.durability {
+            Durability::PerFile => {
+                if let Err(e) = sync_all_the_way_down(path) {
+                    self.failures.record(&e);
+                }
+            }
+            Durability::PerDirectoryBatch | Durability::Syncfs => {
+                if let Some(parent) = path.parent() {
+                    self.unsynced.insert(parent.to_path_buf());
+                }
+            }
+            Durability::None => {}
+        }
+    }
+
+    /// Sync everything changed since the last time, as `durability` asks.
+    pub fn sync(&mut self) {
+        // directories since pruned were synced away with their parents
+        let unsynced: Vec<PathBuf> = std::mem::take(&mut self.unsynced)
+            .into_iter()
+            .filter(|dir| dir.exists())
+            .collect();
+        let mut filesystems = HashSet::new();
+        for dir in &unsynced {
+            let result = match self.durability {
+                Durability::Syncfs => fs::File::open(dir)
+                    .and_then(|file| {
+                        if filesystems.insert(file.metadata()?.dev()) {
+                            syncfs(&file)?;
+                        }
+                        Ok(())
+                    })
+                    .with_context(|| anyhow!("syncing the filesystem of {dir:?}")),
+                _ => sync_dirs([dir.as_path()]),
+            };
+            if let Err(e) = result {
+                self.failures.record(&e);
+            }
+        }
+    }
+
+    /// Usage to journal around deleting `path`, if there's a journal.
+    fn journal_usage(&self, path: &Path) -> Result<Option<Usage>> {
+        self.journal.as_ref().map(|j| j.usage(path)).transpose()
+    }
+
+    /// Remove directories below any of `roots` left empty by removals, deepest first,
+    /// then sync what's left above them, or note it to `sync` later.
+    pub fn prune_empty_dirs(&mut self, roots: &[PathBuf]) -> Result<usize> {
+        let below_a_root = |dir: &Path| {
+            roots
+                .iter()
+                .any(|root| dir.starts_with(root) && dir != root)
+        };
+        let mut queue: BTreeSet<PathBuf> = std::mem::take(&mut self.touched)
+            .into_iter()
+            .filter(|dir| below_a_root(dir))
+            .collect();
+        let mut removed = BTreeSet::new();
+        // children sort after their parents, so this goes deepest first
+        while let Some(dir) = queue.pop_last() {
+            if let Err(e) = fs::remove_dir(&dir) {
+                debug!("not pruning {dir:?}: {e}");
+                continue;
+            }
+            info!("pruned empty directory {dir:?}");
+            if let Some(parent) = dir.parent()
+                && below_a_root(parent)
+            {
+                queue.insert(parent.to_path_buf());
+            }
+            removed.insert(dir);
+        }
+
+        let to_sync = ancestors_of(removed.iter().map(PathBuf::as_path))
+            .into_iter()
+            .filter(|dir| !removed.contains(*dir));
+        for dir in to_sync {
+            match self.durability {
+                Durability::PerFile => {
+                    if let Err(e) = sync_dirs([dir]) {
+                        self.failures.record(&e);
+                    }
+                }
+                Durability::PerDirectoryBatch | Durability::Syncfs => {
+                    self.unsynced.insert(dir.to_path_buf());
+                }
+                Durability::None => {}
+            }
+        }
+        Ok(removed.len())
+    }
+}
+
+/// Every directory above any of `paths`, up to `/`, once each.
+fn ancestors_of<'p>(paths: impl IntoIterator<Item = &'p Path>) -> BTreeSet<&'p Path> {
+    paths
+        .into_iter()
+        .flat_map(|path| path.ancestors().skip(1))
+        .collect()
+}
+
+fn sync_dirs<'p>(dirs: impl IntoIterator<Item = &'p Path>) -> Result<()> {
+    for dir in dirs {
+        fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| anyhow!("syncing {dir:?}"))?;
+    }
+    Ok(())
+}
+
+fn sync_all_the_way_down(starting_file: impl AsRef<Path>) -> Result<()> {
+    sync_dirs(ancestors_of([starting_file.as_ref()]))
+}
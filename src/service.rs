@@ -0,0 +1,130 @@
+use anyhow::{Context, Result};
+use log::warn;
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to block at most, so a stop or a due watchdog ping is never waited on for long.
+pub const TICK: Duration = Duration::from_secs(1);
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// When the watchdog was last pinged.
+static LAST_PING: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Returned from a cleanup stopped part way through by `SIGTERM` or `SIGINT`.
+#[derive(Debug)]
+pub struct Stopped;
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "asked to stop")
+    }
+}
+
+impl std::error::Error for Stopped {}
+
+/// Have `SIGTERM` and `SIGINT` stop things at the next file, rather than part way through
+/// one, or leaving the rest of a recording behind.
+pub fn stop_on_signals() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGTERM, Signal::SIGINT] {
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        unsafe { sigaction(signal, &action) }.with_context(|| format!("handling {signal}"))?;
+    }
+    Ok(())
+}
+
+extern "C" fn on_signal(_: nix::libc::c_int) {
+    STOPPING.store(true, Ordering::Relaxed);
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+/// Tell systemd about our state (e.g. `READY=1`), if it's listening on `$NOTIFY_SOCKET`,
+/// as it is for a `Type=notify` unit.
+pub fn notify(state: &str) {
+    let Some(socket) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send(&socket, state) {
+        warn!("can't tell systemd {state:?}: {e:#}");
+    }
+}
+
+fn send(socket: &OsStr, state: &str) -> Result<()> {
+    let address = match socket.as_encoded_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?
+        .send_to_addr(state.as_bytes(), &address)
+        .with_context(|| format!("sending to {socket:?}"))?;
+    Ok(())
+}
+
+/// Ping systemd's watchdog, if it has one for us and half its timeout has passed since the
+/// last ping. Called between files, and while waiting, so it only stops if we hang.
+pub fn keep_alive() {
+    let Some(timeout) = watchdog_timeout() else {
+        return;
+    };
+    let mut last_ping = LAST_PING.lock().expect("pinging panicked");
+    if last_ping.is_none_or(|last_ping| last_ping.elapsed() >= timeout / 2) {
+        notify("WATCHDOG=1");
+        *last_ping = Some(Instant::now());
+    }
+}
+
+/// From `$WATCHDOG_USEC`, if `$WATCHDOG_PID` is unset or us.
+fn watchdog_timeout() -> Option<Duration> {
+    static TIMEOUT: OnceLock<Option<Duration>> = OnceLock::new();
+    *TIMEOUT.get_or_init(|| {
+        if let Ok(pid) = env::var("WATCHDOG_PID")
+            && pid != process::id().to_string()
+        {
+            return None;
+        }
+        let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        Some(Duration::from_micros(usec))
+    })
+}
+
+/// Sleep for `duration`, keeping the watchdog happy, returning false if asked to stop.
+pub fn sleep(duration: Duration) -> bool {
+    let until = Instant::now() + duration;
+    loop {
+        keep_alive();
+        if stopping() {
+            return false;
+        }
+        let left = until.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(TICK));
+    }
+}
+
+/// `interval`, plus up to `jitter` more, picked at random, to the second.
+pub fn jittered(interval: Duration, jitter: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    let extra = jitter.mul_f64(random as f64 / u64::MAX as f64);
+    interval + Duration::from_secs(extra.as_secs())
+}
@@ -0,0 +1,237 @@
+use anyhow::{Context, Result, anyhow};
+use log::warn;
+use nix::sys::statvfs::{Statvfs, statvfs};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::AddAssign;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::mb;
+
+/// What counts as clean enough; every target that is set has to be met.
+#[derive(Debug, Clone, Default)]
+pub struct Targets {
+    pub use_percentage: Option<u8>,
+    /// don't start cleaning for `use_percentage` until usage reaches this
+    pub high_watermark: Option<u8>,
+    pub free_bytes: Option<u64>,
+    pub free_inodes: Option<u64>,
+    /// keep the directories themselves below this size, whatever else is on the filesystem
+    pub max_size: Option<u64>,
+}
+
+/// The state of a filesystem, as far as the targets are concerned.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Usage {
+    pub use_percentage: u8,
+    pub bytes_available: u64,
+    pub inodes_free: u64,
+    /// of the directories being cleaned, if it's been measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tree_bytes: Option<u64>,
+}
+
+/// Some bytes and inodes, either needed or freed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Space {
+    pub bytes: u64,
+    pub inodes: u64,
+}
+
+impl AddAssign for Space {
+    fn add_assign(&mut self, other: Space) {
+        self.bytes += other.bytes;
+        self.inodes += other.inodes;
+    }
+}
+
+impl Space {
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0 && self.inodes == 0
+    }
+
+    pub fn covers(&self, needed: &Space) -> bool {
+        self.bytes >= needed.bytes && self.inodes >= needed.inodes
+    }
+
+    pub fn saturating_sub(self, other: Space) -> Space {
+        Space {
+            bytes: self.bytes.saturating_sub(other.bytes),
+            inodes: self.inodes.saturating_sub(other.inodes),
+        }
+    }
+}
+
+impl Usage {
+    /// Whether a cleanup should start: like `!meets`, but waiting for the high watermark.
+    pub fn needs_cleanup(&self, targets: &Targets) -> bool {
+        match targets.high_watermark {
+            Some(high_watermark) => {
+                let ignoring_percentage = Targets {
+                    use_percentage: None,
+                    ..targets.clone()
+                };
+                self.use_percentage >= high_watermark || !self.meets(&ignoring_percentage)
+            }
+            None => !self.meets(targets),
+        }
+    }
+
+    pub fn meets(&self, targets: &Targets) -> bool {
+        targets
+            .use_percentage
+            .is_none_or(|target| self.use_percentage < target)
+            && targets
+                .free_bytes
+                .is_none_or(|target| self.bytes_available >= target)
+            && targets
+                .free_inodes
+                .is_none_or(|target| self.inodes_free >= target)
+            && targets
+                .max_size
+                .is_none_or(|target| self.tree_bytes.is_none_or(|tree| tree <= target))
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}% used, {:.1}MB available, {} inodes free",
+            self.use_percentage,
+            mb(self.bytes_available),
+            self.inodes_free
+        )?;
+        if let Some(tree_bytes) = self.tree_bytes {
+            write!(f, ", {:.1}MB in the directories", mb(tree_bytes))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Targets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(target) = self.use_percentage {
+            parts.push(format!("<{target}% used"));
+        }
+        if let Some(high_watermark) = self.high_watermark {
+            parts.push(format!("starting at {high_watermark}% used"));
+        }
+        if let Some(target) = self.free_bytes {
+            parts.push(format!("{:.1}MB available", mb(target)));
+        }
+        if let Some(target) = self.free_inodes {
+            parts.push(format!("{target} inodes free"));
+        }
+        if let Some(target) = self.max_size {
+            parts.push(format!("{:.1}MB in the directories", mb(target)));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl fmt::Display for Space {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.1}MB and {} inodes", mb(self.bytes), self.inodes)
+    }
+}
+
+/// With `df_compatible`, percentages are of the space available to normal users, like `df`
+/// shows, rather than of the whole filesystem, including the blocks reserved for root.
+pub fn read_usage(directory: impl AsRef<Path>, df_compatible: bool) -> Result<Usage> {
+    let stat = statvfs(directory.as_ref())?;
+    Ok(Usage {
+        use_percentage: use_percentage(&stat, df_compatible)?,
+        bytes_available: stat.blocks_available() * stat.fragment_size(),
+        inodes_free: stat.files_free(),
+        tree_bytes: None,
+    })
+}
+
+fn use_percentage(stat: &Statvfs, df_compatible: bool) -> Result<u8> {
+    let total_blocks = stat.blocks();
+    let free_blocks = stat.blocks_free();
+
+    if df_compatible {
+        let used_blocks = total_blocks - free_blocks;
+        let usable_blocks = used_blocks + stat.blocks_available();
+        if usable_blocks == 0 {
+            return Ok(0);
+        }
+        return Ok(u8::try_from((used_blocks * 100).div_ceil(usable_blocks))?);
+    }
+
+    let use_percentage = u8::try_from(100u64 - (free_blocks * 100 / total_blocks))?;
+    Ok(use_percentage)
+}
+
+pub fn compute_to_free(
+    directory: impl AsRef<Path>,
+    targets: &Targets,
+    df_compatible: bool,
+) -> Result<Space> {
+    let stat = statvfs(directory.as_ref())?;
+    let block_size = stat.fragment_size();
+    let mut to_free = Space::default();
+
+    if let Some(target_use_percentage) = targets.use_percentage {
+        let total_blocks = stat.blocks();
+        let free_blocks = stat.blocks_free();
+        let used_blocks = total_blocks - free_blocks;
+        let usable_blocks = if df_compatible {
+            used_blocks + stat.blocks_available()
+        } else {
+            total_blocks
+        };
+        let target_used_blocks = usable_blocks * u64::from(target_use_percentage) / 100;
+        to_free.bytes = used_blocks.saturating_sub(target_used_blocks) * block_size;
+    }
+
+    if let Some(target_free_bytes) = targets.free_bytes {
+        let available = stat.blocks_available() * block_size;
+        to_free.bytes = to_free
+            .bytes
+            .max(target_free_bytes.saturating_sub(available));
+    }
+
+    if let Some(target_free_inodes) = targets.free_inodes {
+        to_free.inodes = target_free_inodes.saturating_sub(stat.files_free());
+    }
+
+    Ok(to_free)
+}
+
+/// Canonicalise `directories` and group them by the filesystem they're on, dropping any
+/// inside another, as their files are already covered.
+pub fn group_by_filesystem(directories: &[PathBuf]) -> Result<BTreeMap<u64, Vec<PathBuf>>> {
+    let mut canonical = Vec::with_capacity(directories.len());
+    for directory in directories {
+        canonical.push(
+            directory
+                .canonicalize()
+                .with_context(|| anyhow!("resolving {directory:?}"))?,
+        );
+    }
+    canonical.sort();
+    canonical.dedup();
+
+    let mut filesystems: BTreeMap<u64, Vec<PathBuf>> = BTreeMap::new();
+    for directory in &canonical {
+        if let Some(outer) = canonical
+            .iter()
+            .find(|outer| *outer != directory && directory.starts_with(outer))
+        {
+            warn!("{directory:?} is inside {outer:?}, ignoring it");
+            continue;
+        }
+        let dev = directory
+            .metadata()
+            .with_context(|| anyhow!("reading {directory:?}"))?
+            .dev();
+        filesystems.entry(dev).or_default().push(directory.clone());
+    }
+    Ok(filesystems)
+}
@@ -0,0 +1,226 @@
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use crate::candidates::{FileInfo, file_info, metadata_to_file_info};
+use crate::index::{Index, IndexedDir};
+use crate::unix_now;
+
+/// Call `on_dir` with the regular files in each directory below `directories`, except in
+/// `skip_dirs`, one directory at a time, in no particular order. With more than one
+/// thread, directories are read in parallel, which helps when each read waits on a disk
+/// seek. With an `index`, directories that haven't changed since it was last updated
+/// aren't read again, and it's updated with those that were.
+pub fn walk_files(
+    directories: &[PathBuf],
+    skip_dirs: &[PathBuf],
+    threads: usize,
+    index: Option<&mut Index>,
+    mut on_dir: impl FnMut(Vec<Result<FileInfo>>),
+) -> Result<()> {
+    let walker = Walker {
+        skip_dirs,
+        previous: index.as_deref(),
+        now: unix_now()?,
+        reused: AtomicUsize::new(0),
+    };
+    let queue = Queue {
+        state: Mutex::new(QueueState {
+            dirs: directories
+                .iter()
+                .filter(|dir| !skip_dirs.contains(dir))
+                .cloned()
+                .collect(),
+            reading: 0,
+            indexed: Vec::new(),
+        }),
+        changed: Condvar::new(),
+    };
+
+    if threads <= 1 {
+        while let Some(dir) = queue.next() {
+            let (files, subdirs, indexed) = walker.read_dir(&dir);
+            queue.done(dir, subdirs, indexed);
+            on_dir(files);
+        }
+    } else {
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                let sender = sender.clone();
+                let (walker, queue) = (&walker, &queue);
+                scope.spawn(move || {
+                    while let Some(dir) = queue.next() {
+                        let (files, subdirs, indexed) = walker.read_dir(&dir);
+                        queue.done(dir, subdirs, indexed);
+                        let _ = sender.send(files);
+                    }
+                });
+            }
+            drop(sender);
+            for files in receiver {
+                on_dir(files);
+            }
+        });
+    }
+
+    let walked = queue.state.into_inner().expect("walker panicked").indexed;
+    let reused = walker.reused.into_inner();
+    if let Some(index) = index {
+        debug!(
+            "indexed {} directories, {reused} unchanged since last time",
+            walked.len()
+        );
+        index.update(directories, walked);
+    }
+    Ok(())
+}
+
+struct Walker<'w> {
+    skip_dirs: &'w [PathBuf],
+    /// the index as it was before this walk
+    previous: Option<&'w Index>,
+    now: u64,
+    reused: AtomicUsize,
+}
+
+impl Walker<'_> {
+    /// The files in `dir`, its subdirectories, and what to index for it if there's an
+    /// index. Like `walkdir`, symlinks aren't followed.
+    fn read_dir(&self, dir: &Path) -> (Vec<Result<FileInfo>>, Vec<PathBuf>, Option<IndexedDir>) {
+        let mut files = Vec::new();
+        let mut indexed = None;
+        if let Some(previous) = self.previous {
+            let metadata = match fs::symlink_metadata(dir) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    files.push(Err(anyhow!(e).context(format!("reading {dir:?}"))));
+                    return (files, Vec::new(), None);
+                }
+            };
+            if let Some(unchanged) = previous.get(dir, &metadata) {
+                return self.reuse(dir, unchanged, &metadata);
+            }
+            indexed = Some(IndexedDir::new(&metadata, self.now));
+        }
+
+        let mut subdirs = Vec::new();
+        let entries = match fs::read_dir(dir).with_context(|| anyhow!("reading {dir:?}")) {
+            Ok(entries) => entries,
+            Err(e) => {
+                files.push(Err(e));
+                return (files, subdirs, None);
+            }
+        };
+        for entry in entries {
+            let result = entry
+                .and_then(|entry| Ok((entry.path(), entry.file_type()?)))
+                .with_context(|| anyhow!("reading {dir:?}"));
+            let (path, file_type) = match result {
+                Ok(entry) => entry,
+                Err(e) => {
+                    files.push(Err(e));
+                    // what's indexed would be missing it
+                    indexed = None;
+                    continue;
+                }
+            };
+            if file_type.is_dir() {
+                if let Some(indexed) = &mut indexed {
+                    indexed.add_subdir(&path);
+                }
+                if !self.skip_dirs.contains(&path) {
+                    subdirs.push(path);
+                }
+            } else if file_type.is_file() {
+                let file = fs::symlink_metadata(&path)
+                    .with_context(|| anyhow!("reading {path:?}"))
+                    .and_then(|metadata| metadata_to_file_info(&path, &metadata));
+                match (&file, &mut indexed) {
+                    (Ok(file), Some(indexed)) => indexed.add_file(file),
+                    (Err(_), _) => indexed = None,
+                    (Ok(_), None) => {}
+                }
+                files.push(file);
+            }
+        }
+        (files, subdirs, indexed)
+    }
+
+    /// Pass on what was indexed for `dir`, which hasn't changed since, looking again at
+    /// files that may have still been being written.
+    fn reuse(
+        &self,
+        dir: &Path,
+        unchanged: &IndexedDir,
+        metadata: &fs::Metadata,
+    ) -> (Vec<Result<FileInfo>>, Vec<PathBuf>, Option<IndexedDir>) {
+        self.reused.fetch_add(1, Ordering::Relaxed);
+        let mut files = Vec::new();
+        let mut indexed = Some(IndexedDir::new(metadata, self.now));
+        let mut subdirs = Vec::new();
+        for subdir in unchanged.subdirs(dir) {
+            if let Some(indexed) = &mut indexed {
+                indexed.add_subdir(&subdir);
+            }
+            if !self.skip_dirs.contains(&subdir) {
+                subdirs.push(subdir);
+            }
+        }
+        for file in unchanged.files(dir) {
+            let file = file.or_else(|path| file_info(&path));
+            match (&file, &mut indexed) {
+                (Ok(file), Some(indexed)) => indexed.add_file(file),
+                (Err(_), _) => indexed = None,
+                (Ok(_), None) => {}
+            }
+            files.push(file);
+        }
+        (files, subdirs, indexed)
+    }
+}
+
+/// Directories waiting to be read, shared between the walker threads.
+struct Queue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+struct QueueState {
+    dirs: Vec<PathBuf>,
+    /// being read, so they may add more
+    reading: usize,
+    indexed: Vec<(PathBuf, IndexedDir)>,
+}
+
+impl Queue {
+    /// The next directory to read, or `None` once every one has been.
+    fn next(&self) -> Option<PathBuf> {
+        let mut state = self.state.lock().expect("walker panicked");
+        loop {
+            if let Some(dir) = state.dirs.pop() {
+                state.reading += 1;
+                return Some(dir);
+            }
+            if state.reading == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).expect("walker panicked");
+        }
+    }
+
+    fn done(&self, dir: PathBuf, subdirs: Vec<PathBuf>, indexed: Option<IndexedDir>) {
+        let mut state = self.state.lock().expect("walker panicked");
+        state.dirs.extend(subdirs);
+        if let Some(indexed) = indexed {
+            state.indexed.push((dir, indexed));
+        }
+        state.reading -= 1;
+        self.changed.notify_all();
+    }
+}